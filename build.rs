@@ -14,6 +14,7 @@ fn main() {
 
     let mut compiler = shaderc::Compiler::new().unwrap();
     let options = shaderc::CompileOptions::new().unwrap();
+    let mut shaders = vec![];
 
     for entry in std::fs::read_dir(&src_path).unwrap() {
         let path = entry.as_ref().unwrap().path();
@@ -37,5 +38,13 @@ fn main() {
                "const {}_{}_SHADER: [u8;{}] = {:?};\n",
                stem, kind_str, binary_result.len(), binary)
             .unwrap();
+        shaders.push((filename.to_str().unwrap().to_string(), format!("{}_{}_SHADER", stem, kind_str)));
     }
+
+    // looked up by file name from pipeline descriptions
+    write!(outfile, "const COMPILED_SHADERS: [(&str, &[u8]); {}] = [\n", shaders.len()).unwrap();
+    for (filename, constant) in shaders {
+        write!(outfile, "    ({:?}, &{}),\n", filename, constant).unwrap();
+    }
+    write!(outfile, "];\n").unwrap();
 }
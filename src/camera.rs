@@ -0,0 +1,325 @@
+use glm::{Mat4, Vec3, vec3};
+use winit::{ElementState, KeyboardInput, MouseButton, MouseScrollDelta,
+            VirtualKeyCode, WindowEvent};
+
+const DEFAULT_EYE: [f32; 3] = [2.0, 2.0, 2.0];
+const DEFAULT_TARGET: [f32; 3] = [0.0, 0.0, 0.0];
+
+/// Radians per pixel of mouse drag.
+const ROTATE_SPEED: f32 = 0.005;
+/// Fraction of the orbit distance per scroll line.
+const ZOOM_SPEED: f32 = 0.1;
+/// World units per scroll line in fly mode.
+const DOLLY_SPEED: f32 = 0.25;
+/// World units per second for keyboard movement.
+const MOVE_SPEED: f32 = 2.0;
+const MIN_DISTANCE: f32 = 0.1;
+/// Just short of straight up/down, where `look_at` degenerates.
+const MAX_PITCH: f32 = 1.55;
+/// Pixels per scroll line for touchpads reporting pixel deltas.
+const PIXELS_PER_LINE: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    /// Rotates around and zooms towards a target point.
+    Orbit,
+    /// Moves freely, looking along the mouse direction.
+    Fly,
+}
+
+#[derive(Debug, Default)]
+struct Movement {
+    forward: bool,
+    back: bool,
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+}
+
+/// A Z-up camera driven by winit events: drag with the left mouse button
+/// to rotate, scroll to zoom, WASD/QE to move, Tab to switch between
+/// orbit and fly mode and R to reset the view.
+#[derive(Debug)]
+pub struct Camera {
+    mode: CameraMode,
+    /// Orbit centre, only used in orbit mode.
+    target: Vec3,
+    /// Eye position, only used in fly mode.
+    position: Vec3,
+    distance: f32,
+    yaw: f32,
+    pitch: f32,
+    dragging: bool,
+    last_cursor: Option<(f64, f64)>,
+    movement: Movement,
+}
+
+impl Camera {
+    pub fn new() -> Camera {
+        let eye = glm::make_vec3(&DEFAULT_EYE);
+        let target = glm::make_vec3(&DEFAULT_TARGET);
+        let offset = eye - target;
+        let distance = glm::length(&offset);
+
+        Camera {
+            mode: CameraMode::Orbit,
+            target,
+            position: eye,
+            distance,
+            yaw: offset.y.atan2(offset.x),
+            pitch: (offset.z / distance).asin(),
+            dragging: false,
+            last_cursor: None,
+            movement: Movement::default(),
+        }
+    }
+
+    pub fn mode(&self) -> CameraMode {
+        self.mode
+    }
+
+    pub fn reset(&mut self) {
+        let mode = self.mode;
+        *self = Camera::new();
+        self.mode = mode;
+    }
+
+    pub fn toggle_mode(&mut self) {
+        match self.mode {
+            CameraMode::Orbit => {
+                self.position = self.eye();
+                self.mode = CameraMode::Fly;
+            },
+            CameraMode::Fly => {
+                self.target = self.position + self.forward() * self.distance;
+                self.mode = CameraMode::Orbit;
+            },
+        }
+    }
+
+    pub fn eye(&self) -> Vec3 {
+        match self.mode {
+            CameraMode::Orbit => self.target - self.forward() * self.distance,
+            CameraMode::Fly => self.position,
+        }
+    }
+
+    pub fn view_matrix(&self) -> Mat4 {
+        let eye = self.eye();
+        glm::look_at(&eye, &(eye + self.forward()), &vec3(0.0, 0.0, 1.0))
+    }
+
+    /// Returns true if the event was used by the camera.
+    pub fn handle_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+                self.dragging = *state == ElementState::Pressed;
+                true
+            },
+            WindowEvent::CursorMoved { position, .. } => {
+                if let (true, Some((x, y))) = (self.dragging, self.last_cursor) {
+                    self.rotate((position.x - x) as f32, (position.y - y) as f32);
+                }
+                self.last_cursor = Some((position.x, position.y));
+                self.dragging
+            },
+            WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) =>
+                        position.y as f32 / PIXELS_PER_LINE,
+                };
+                self.zoom(lines);
+                true
+            },
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput { state, virtual_keycode: Some(key), .. },
+                ..
+            } => self.handle_key(*key, *state == ElementState::Pressed),
+            WindowEvent::Focused(false) => {
+                self.dragging = false;
+                self.movement = Movement::default();
+                false
+            },
+            _ => false,
+        }
+    }
+
+    /// Applies keyboard movement for a frame lasting `dt` seconds.
+    pub fn update(&mut self, dt: f32) {
+        let forward = match self.mode {
+            CameraMode::Fly => self.forward(),
+            // panning in orbit mode stays level with the ground
+            CameraMode::Orbit => -vec3(self.yaw.cos(), self.yaw.sin(), 0.0),
+        };
+        let right = glm::normalize(&glm::cross(&forward, &vec3(0.0, 0.0, 1.0)));
+        let up = vec3(0.0, 0.0, 1.0);
+
+        let m = &self.movement;
+        let axis = |positive: bool, negative: bool| {
+            (positive as i32 - negative as i32) as f32
+        };
+        let direction = forward * axis(m.forward, m.back)
+            + right * axis(m.right, m.left)
+            + up * axis(m.up, m.down);
+        if direction == vec3(0.0, 0.0, 0.0) {
+            return;
+        }
+
+        let step = glm::normalize(&direction) * MOVE_SPEED * dt;
+        match self.mode {
+            CameraMode::Orbit => self.target += step,
+            CameraMode::Fly => self.position += step,
+        }
+    }
+
+    fn handle_key(&mut self, key: VirtualKeyCode, pressed: bool) -> bool {
+        let movement = match key {
+            VirtualKeyCode::W => &mut self.movement.forward,
+            VirtualKeyCode::S => &mut self.movement.back,
+            VirtualKeyCode::A => &mut self.movement.left,
+            VirtualKeyCode::D => &mut self.movement.right,
+            VirtualKeyCode::E => &mut self.movement.up,
+            VirtualKeyCode::Q => &mut self.movement.down,
+            VirtualKeyCode::Tab => {
+                if pressed {
+                    self.toggle_mode();
+                }
+                return true;
+            },
+            VirtualKeyCode::R => {
+                if pressed {
+                    self.reset();
+                }
+                return true;
+            },
+            _ => return false,
+        };
+        *movement = pressed;
+        true
+    }
+
+    /// Direction the camera looks in.
+    fn forward(&self) -> Vec3 {
+        -vec3(
+            self.pitch.cos() * self.yaw.cos(),
+            self.pitch.cos() * self.yaw.sin(),
+            self.pitch.sin())
+    }
+
+    fn rotate(&mut self, dx: f32, dy: f32) {
+        self.yaw -= dx * ROTATE_SPEED;
+        self.pitch = (self.pitch + dy * ROTATE_SPEED)
+            .max(-MAX_PITCH)
+            .min(MAX_PITCH);
+    }
+
+    fn zoom(&mut self, lines: f32) {
+        match self.mode {
+            CameraMode::Orbit => {
+                self.distance = (self.distance * (1.0 - lines * ZOOM_SPEED))
+                    .max(MIN_DISTANCE);
+            },
+            CameraMode::Fly => {
+                self.position += self.forward() * lines * DOLLY_SPEED;
+            },
+        }
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!(glm::distance(&actual, &expected) < 1e-4,
+                "{:?} is not near {:?}", actual, expected);
+    }
+
+    fn views_near(a: &Mat4, b: &Mat4) -> bool {
+        a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-4)
+    }
+
+    #[test]
+    fn starts_at_the_default_eye() {
+        let camera = Camera::new();
+        assert_eq!(camera.mode(), CameraMode::Orbit);
+        assert_near(camera.eye(), glm::make_vec3(&DEFAULT_EYE));
+    }
+
+    #[test]
+    fn reset_restores_the_initial_view_but_keeps_the_mode() {
+        let initial = Camera::new().view_matrix();
+        let mut camera = Camera::new();
+        camera.rotate(120.0, -40.0);
+        camera.zoom(3.0);
+        camera.handle_key(VirtualKeyCode::W, true);
+        camera.update(0.5);
+        camera.toggle_mode();
+        assert!(!views_near(&camera.view_matrix(), &initial));
+
+        camera.reset();
+        assert_eq!(camera.mode(), CameraMode::Fly);
+        assert!(views_near(&camera.view_matrix(), &initial));
+        // held keys are released too
+        camera.update(0.5);
+        assert!(views_near(&camera.view_matrix(), &initial));
+    }
+
+    #[test]
+    fn scrolling_stops_at_the_minimum_orbit_distance() {
+        let mut camera = Camera::new();
+        for _ in 0..100 {
+            camera.zoom(5.0);
+        }
+        assert_eq!(camera.distance, MIN_DISTANCE);
+        assert!((glm::distance(&camera.eye(), &camera.target) - MIN_DISTANCE).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pitch_stops_short_of_straight_up_and_down() {
+        let mut camera = Camera::new();
+        camera.rotate(0.0, 10_000.0);
+        assert_eq!(camera.pitch, MAX_PITCH);
+        camera.rotate(0.0, -20_000.0);
+        assert_eq!(camera.pitch, -MAX_PITCH);
+    }
+
+    #[test]
+    fn toggling_the_mode_keeps_the_eye_and_direction() {
+        let mut camera = Camera::new();
+        camera.rotate(50.0, 20.0);
+        let view = camera.view_matrix();
+
+        camera.toggle_mode();
+        assert_eq!(camera.mode(), CameraMode::Fly);
+        assert!(views_near(&camera.view_matrix(), &view));
+
+        camera.toggle_mode();
+        assert_eq!(camera.mode(), CameraMode::Orbit);
+        assert!(views_near(&camera.view_matrix(), &view));
+    }
+
+    #[test]
+    fn flying_forward_moves_along_the_view_direction() {
+        let mut camera = Camera::new();
+        camera.toggle_mode();
+        let eye = camera.eye();
+
+        camera.handle_key(VirtualKeyCode::W, true);
+        camera.update(1.0);
+        assert_near(camera.eye(), eye + camera.forward() * MOVE_SPEED);
+
+        camera.handle_key(VirtualKeyCode::W, false);
+        let eye = camera.eye();
+        camera.update(1.0);
+        assert_near(camera.eye(), eye);
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::PathBuf;
+
+use game::config::{AdapterPreference, Config, PresentMode};
+
+const USAGE: &str = "\
+Usage: game [options]
+
+Options:
+    --config <path>       TOML config file (default: game.toml if present)
+    --model <path>        OBJ, glTF or GLB model to display, repeat to show
+                          several models (default: models/chalet.obj)
+    --translate <x,y,z>   move the last --model by this offset
+    --texture <path>      texture applied to the model (default: textures/chalet.jpg)
+    --width <pixels>      initial window width
+    --height <pixels>     initial window height
+    --msaa <samples>      upper limit for multisampling, a power of two
+    --frames-in-flight <n>
+                          frames the CPU may record ahead of the GPU
+    --adapter <adapter>   GPU to use: an index from --list-adapters, discrete,
+                          integrated, virtual, cpu or part of its name
+    --list-adapters       print the available GPUs and exit
+    --present-mode <mode> fifo (vsync), mailbox, immediate or relaxed, falls
+                          back to a supported mode. Press V to cycle at runtime
+    --no-srgb             render to a linear target and pass texture values
+                          through unchanged
+    --log <filter>        log filter used when RUST_LOG is unset
+    --headless [output]   render a single frame to a PNG without a window
+                          (default output: frame.png)
+    --help                print this message
+
+Options given on the command line override the config file.";
+
+/// Command line arguments. Settings that can also come from the config
+/// file are `None` unless given, see `apply`.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    /// Replace the configured models when not empty.
+    pub models: Vec<PathBuf>,
+    /// Offset of each model in `models`.
+    pub translations: Vec<[f32; 3]>,
+    pub texture: Option<PathBuf>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub msaa_samples: Option<u8>,
+    pub frames_in_flight: Option<usize>,
+    pub present_mode: Option<PresentMode>,
+    pub adapter: Option<AdapterPreference>,
+    pub list_adapters: bool,
+    pub srgb: Option<bool>,
+    pub log_filter: Option<String>,
+    pub headless_output: Option<PathBuf>,
+}
+
+impl Args {
+    /// Parses the process arguments, exiting with a usage message on
+    /// anything it does not understand.
+    pub fn parse() -> Args {
+        match Args::parse_from(std::env::args().skip(1)) {
+            Ok(args) => args,
+            Err(msg) => {
+                eprintln!("{}\n\n{}", msg, USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn parse_from<I: Iterator<Item = String>>(args: I) -> Result<Args, String> {
+        let mut result = Args::default();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => result.config = Some(value(&arg, args.next())?.into()),
+                "--model" => {
+                    result.models.push(value(&arg, args.next())?.into());
+                    result.translations.push([0.0; 3]);
+                },
+                "--translate" => {
+                    let offset = parse_vec3(&value(&arg, args.next())?)
+                        .ok_or_else(|| format!("{} expects x,y,z", arg))?;
+                    *result.translations
+                        .last_mut()
+                        .ok_or_else(|| format!("{} must follow --model", arg))? = offset;
+                },
+                "--texture" => result.texture = Some(value(&arg, args.next())?.into()),
+                "--width" => result.width = Some(number(&arg, args.next())?),
+                "--height" => result.height = Some(number(&arg, args.next())?),
+                "--msaa" => result.msaa_samples = Some(number(&arg, args.next())?),
+                "--frames-in-flight" => result.frames_in_flight = Some(number(&arg, args.next())?),
+                "--adapter" => result.adapter = Some(value(&arg, args.next())?.into()),
+                "--list-adapters" => result.list_adapters = true,
+                "--present-mode" => result.present_mode = Some(value(&arg, args.next())?.parse()?),
+                "--no-srgb" => result.srgb = Some(false),
+                "--log" => result.log_filter = Some(value(&arg, args.next())?),
+                "--headless" => {
+                    let output = match args.peek() {
+                        Some(next) if !next.starts_with("--") => args.next().unwrap(),
+                        _ => "frame.png".to_string(),
+                    };
+                    result.headless_output = Some(output.into());
+                },
+                "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                },
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Overrides the settings in `config` that were given on the command line.
+    pub fn apply(&self, config: &mut Config) {
+        if !self.models.is_empty() {
+            config.assets.models = self.models.clone();
+        }
+        if let Some(texture) = &self.texture {
+            config.assets.texture = texture.clone();
+        }
+        if let Some(width) = self.width {
+            config.window.width = width;
+        }
+        if let Some(height) = self.height {
+            config.window.height = height;
+        }
+        if let Some(samples) = self.msaa_samples {
+            config.rendering.msaa_samples = samples;
+        }
+        if let Some(frames) = self.frames_in_flight {
+            config.rendering.frames_in_flight = frames;
+        }
+        if let Some(mode) = self.present_mode {
+            config.rendering.present_mode = mode;
+        }
+        if let Some(adapter) = &self.adapter {
+            config.rendering.adapter = Some(adapter.clone());
+        }
+        if let Some(srgb) = self.srgb {
+            config.rendering.srgb = srgb;
+        }
+        if let Some(filter) = &self.log_filter {
+            config.log_filter = filter.clone();
+        }
+        // single frames shouldn't leave files in the user's cache directory
+        if self.headless_output.is_some() {
+            config.rendering.pipeline_cache = false;
+        }
+    }
+}
+
+fn value(arg: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("Missing value for {}", arg))
+}
+
+fn number<T: std::str::FromStr>(arg: &str, value: Option<String>) -> Result<T, String> {
+    let value = self::value(arg, value)?;
+    value.parse().map_err(|_| format!("{} expects a number, got {}", arg, value))
+}
+
+fn parse_vec3(value: &str) -> Option<[f32; 3]> {
+    let parts = value
+        .split(',')
+        .map(|part| part.trim().parse::<f32>().ok())
+        .collect::<Option<Vec<f32>>>()?;
+    match parts.as_slice() {
+        &[x, y, z] => Some([x, y, z]),
+        _ => None,
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::consts::APP_TITLE;
+use crate::rendering::AssetPaths;
+use crate::window::{DEFAULT_WIDTH, DEFAULT_HEIGHT};
+
+/// Read from the working directory when no `--config` is given.
+pub const DEFAULT_CONFIG_PATH: &str = "game.toml";
+
+/// Viewer settings, read from a TOML file. Every field is optional and
+/// falls back to its default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `env_logger` filter, used unless `RUST_LOG` is set.
+    pub log_filter: String,
+    pub window: WindowConfig,
+    pub rendering: RenderingConfig,
+    pub assets: AssetPaths,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub title: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderingConfig {
+    /// Upper limit for MSAA, the highest supported count below it is used.
+    pub msaa_samples: u8,
+    pub frames_in_flight: usize,
+    /// Requested number of swapchain images, clamped to what the surface
+    /// supports. The driver's minimum is used when unset.
+    pub swapchain_images: Option<u32>,
+    /// Used if the surface supports it, see `PresentMode::fallbacks`.
+    pub present_mode: PresentMode,
+    /// GPU to render with. The default prefers discrete GPUs.
+    pub adapter: Option<AdapterPreference>,
+    /// Prefer an sRGB render target and decode color textures from sRGB,
+    /// so shading happens in linear space and is gamma encoded for display.
+    /// Off, texture values are passed through unchanged to a linear target.
+    pub srgb: bool,
+    /// RGBA, in linear space when `srgb` is on.
+    pub clear_color: [f32; 4],
+    /// Keep compiled pipelines in the user's cache directory between runs.
+    pub pipeline_cache: bool,
+}
+
+/// How finished frames are handed to the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresentMode {
+    /// Vsync, always supported.
+    Fifo,
+    /// Vsync without tearing, but frames are never waited for.
+    Mailbox,
+    /// No vsync, frames can tear.
+    Immediate,
+    /// Vsync unless a frame is late, which is shown immediately.
+    Relaxed,
+}
+
+impl PresentMode {
+    pub const ALL: [PresentMode; 4] = [
+        PresentMode::Fifo,
+        PresentMode::Mailbox,
+        PresentMode::Immediate,
+        PresentMode::Relaxed,
+    ];
+
+    /// Modes to try in order when this one is preferred. Each keeps as much
+    /// of the preference as it can, ending with `Fifo`.
+    pub fn fallbacks(self) -> &'static [PresentMode] {
+        match self {
+            PresentMode::Fifo => &[PresentMode::Fifo],
+            PresentMode::Mailbox => &[PresentMode::Mailbox, PresentMode::Fifo],
+            PresentMode::Immediate => &[
+                PresentMode::Immediate,
+                PresentMode::Mailbox,
+                PresentMode::Relaxed,
+                PresentMode::Fifo,
+            ],
+            PresentMode::Relaxed => &[PresentMode::Relaxed, PresentMode::Fifo],
+        }
+    }
+}
+
+impl std::str::FromStr for PresentMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(PresentMode::Fifo),
+            "mailbox" => Ok(PresentMode::Mailbox),
+            "immediate" => Ok(PresentMode::Immediate),
+            "relaxed" => Ok(PresentMode::Relaxed),
+            _ => Err(format!(
+                "unknown present mode {}, expected fifo, mailbox, immediate or relaxed", s)),
+        }
+    }
+}
+
+/// How to pick a GPU, parsed from an index, a device type or otherwise a
+/// name.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "String")]
+pub enum AdapterPreference {
+    /// Position in the list printed by `--list-adapters`.
+    Index(usize),
+    Type(AdapterType),
+    /// Case insensitive part of the adapter name.
+    Name(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdapterType {
+    Discrete,
+    Integrated,
+    Virtual,
+    Cpu,
+}
+
+impl From<String> for AdapterPreference {
+    fn from(s: String) -> Self {
+        if let Ok(index) = s.parse() {
+            return AdapterPreference::Index(index);
+        }
+        match s.to_lowercase().as_str() {
+            "discrete" => AdapterPreference::Type(AdapterType::Discrete),
+            "integrated" => AdapterPreference::Type(AdapterType::Integrated),
+            "virtual" => AdapterPreference::Type(AdapterType::Virtual),
+            "cpu" => AdapterPreference::Type(AdapterType::Cpu),
+            _ => AdapterPreference::Name(s),
+        }
+    }
+}
+
+impl fmt::Display for AdapterPreference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdapterPreference::Index(index) => write!(f, "adapter {}", index),
+            AdapterPreference::Type(kind) => write!(f, "{:?} adapter", kind),
+            AdapterPreference::Name(name) => write!(f, "adapter named {:?}", name),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_filter: "trace".to_string(),
+            window: WindowConfig::default(),
+            rendering: RenderingConfig::default(),
+            assets: AssetPaths::default(),
+        }
+    }
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            title: APP_TITLE.to_string(),
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+        }
+    }
+}
+
+impl Default for RenderingConfig {
+    fn default() -> Self {
+        RenderingConfig {
+            msaa_samples: 64,
+            frames_in_flight: 2,
+            swapchain_images: None,
+            present_mode: PresentMode::Fifo,
+            adapter: None,
+            srgb: true,
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            pipeline_cache: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) =>
+                write!(f, "can't read config {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) =>
+                write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads `path` if given, otherwise `DEFAULT_CONFIG_PATH` if it exists,
+    /// otherwise the defaults.
+    pub fn find(path: Option<&Path>) -> Result<Config, ConfigError> {
+        match path {
+            Some(path) => Config::load(path),
+            None if Path::new(DEFAULT_CONFIG_PATH).is_file() =>
+                Config::load(Path::new(DEFAULT_CONFIG_PATH)),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        Config::from_toml(&text)
+            .map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Checks the values serde can't, call after applying any overrides.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+        if self.window.width == 0 || self.window.height == 0 {
+            return invalid(format!(
+                "window size must be non-zero, got {}x{}",
+                self.window.width, self.window.height));
+        }
+        let samples = self.rendering.msaa_samples;
+        if !samples.is_power_of_two() || samples > 64 {
+            return invalid(format!(
+                "msaa_samples must be a power of two up to 64, got {}", samples));
+        }
+        if self.rendering.frames_in_flight == 0 {
+            return invalid("frames_in_flight must be at least 1".to_string());
+        }
+        if self.rendering.swapchain_images == Some(0) {
+            return invalid("swapchain_images must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+extern crate winit;
+extern crate gfx_hal;
+extern crate log;
+extern crate nalgebra_glm as glm;
+
+mod consts;
+pub mod camera;
+pub mod config;
+pub mod window;
+pub mod rendering;
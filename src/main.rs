@@ -1,23 +1,102 @@
-extern crate winit;
-extern crate gfx_hal;
 extern crate env_logger;
-extern crate log;
+extern crate game;
 extern crate nalgebra_glm as glm;
 
-mod consts;
-mod window;
-mod rendering;
+mod cli;
 
 use env_logger::Env;
-use window::WindowState;
+use game::config::Config;
+use game::rendering;
+use game::rendering::{Assets, RenderError, Scene};
+use game::window::WindowState;
+
+use cli::Args;
 
 fn main() {
-    env_logger::from_env(Env::default().default_filter_or("trace")).init();
+    let args = Args::parse();
+
+    let config = Config::find(args.config.as_ref().map(|path| path.as_path()))
+        .and_then(|mut config| {
+            args.apply(&mut config);
+            config.validate().map(|_| config)
+        });
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    env_logger::from_env(Env::default().default_filter_or(config.log_filter.as_str())).init();
+
+    if args.list_adapters {
+        list_adapters();
+        return;
+    }
+
+    let assets = match Assets::load(&config.assets) {
+        Ok(assets) => assets,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut scene = Scene::new(assets);
+    for (object, offset) in scene.objects_mut().iter_mut().zip(&args.translations) {
+        object.transform = glm::translation(&glm::make_vec3(offset));
+    }
+
+    let result = match args.headless_output {
+        Some(output) => render_headless(&output, scene, &config),
+        None => render_windowed(scene, &config),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut window = WindowState::new();
-    let (backend, _instance) = rendering::create_backend(&mut window);
+fn render_windowed(scene: Scene, config: &Config) -> Result<(), RenderError> {
+    let mut window = WindowState::new(&config.window);
+    let (backend, _instance) = rendering::create_backend(&mut window, &config.rendering)?;
     let mut renderer_state = unsafe {
-        rendering::RendererState::new(backend, window)
+        rendering::RendererState::new(backend, Some(window), scene, &config.rendering)?
     };
-    renderer_state.mainloop();
+    renderer_state.mainloop()
+}
+
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+fn render_headless(
+    output: &std::path::Path, scene: Scene, config: &Config
+) -> Result<(), RenderError> {
+    let (backend, _instance) = rendering::create_headless_backend(
+        &config.rendering, config.window.width, config.window.height)?;
+    let mut renderer_state = unsafe {
+        rendering::RendererState::new(backend, None, scene, &config.rendering)?
+    };
+    renderer_state.render_to_file(output)
+}
+
+#[cfg(not(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal")))]
+fn render_headless(
+    _output: &std::path::Path, _scene: Scene, _config: &Config
+) -> Result<(), RenderError> {
+    Err(RenderError::Unsupported("headless rendering with this backend".to_string()))
+}
+
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+fn list_adapters() {
+    for adapter in rendering::list_adapters() {
+        println!("{}", adapter);
+    }
+}
+
+/// GL adapters come from the window's context, so there is nothing to
+/// list before opening one.
+#[cfg(not(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal")))]
+fn list_adapters() {
+    eprintln!("Listing adapters is not supported with this backend");
+    std::process::exit(1);
 }
@@ -1,32 +1,107 @@
-use log::debug;
-use gfx_hal::{Adapter, Backend, Limits, PhysicalDevice, MemoryType};
+use log::{debug, info};
+use gfx_hal::{Adapter, Backend, Features, Limits, PhysicalDevice};
+use gfx_hal::adapter::DeviceType;
+use gfx_hal::format;
+
+use super::device::required_features;
+use super::error::RenderError;
+use crate::config::{AdapterPreference, AdapterType};
+
+/// Appended to errors about missing adapters.
+#[cfg(feature = "software")]
+const SOFTWARE_HINT: &str =
+    ". Software adapters like lavapipe are found through VK_ICD_FILENAMES";
+#[cfg(not(feature = "software"))]
+const SOFTWARE_HINT: &str = "";
 
 pub(super) struct AdapterState<B: Backend> {
-    pub(super) adapter: Option<Adapter<B>>,
-    pub(super) memory_types: Vec<MemoryType>,
-    pub(super) limits: Limits
+    pub(super) adapter: Adapter<B>,
+    pub(super) limits: Limits,
+    /// Upper limit for `get_max_usable_sample_count`, a power of two.
+    pub(super) max_samples: u8
 }
 
 impl<B: Backend> AdapterState<B> {
-    pub(super) fn new(adapters: &mut Vec<Adapter<B>>) -> Self {
-        for adapter in adapters.iter() {
-            debug!("{:?}", adapter.info);
+    /// Picks the first adapter matching `preference` that has all
+    /// `required_features`, or without a preference the most capable one
+    /// by device type. The `software` feature makes a CPU adapter the
+    /// default preference.
+    pub(super) fn new(
+        adapters: &mut Vec<Adapter<B>>,
+        preference: Option<&AdapterPreference>
+    ) -> Result<Self, RenderError> {
+        #[cfg(feature = "software")]
+        let software = AdapterPreference::Type(AdapterType::Cpu);
+        #[cfg(feature = "software")]
+        let preference = preference.or(Some(&software));
+
+        for (index, adapter) in adapters.iter().enumerate() {
+            debug!("{}: {:?}", index, adapter.info);
         }
 
-        AdapterState::<B>::new_adapter(adapters.remove(0))
+        if adapters.is_empty() {
+            return Err(RenderError::Unsupported(format!(
+                "no graphics adapters found for this backend{}", SOFTWARE_HINT)));
+        }
+
+        let index = match preference {
+            Some(preference) => {
+                let matching: Vec<usize> = (0..adapters.len())
+                    .filter(|&index| matches(preference, index, &adapters[index]))
+                    .collect();
+                if matching.is_empty() {
+                    return Err(RenderError::Unsupported(format!(
+                        "no {} found, see --list-adapters{}", preference, SOFTWARE_HINT)));
+                }
+                *matching
+                    .iter()
+                    .find(|&&index| missing_features(&adapters[index]).is_empty())
+                    .ok_or_else(|| RenderError::Unsupported(format!(
+                        "{} does not support the required features {:?}",
+                        adapters[matching[0]].info.name,
+                        missing_features(&adapters[matching[0]]))))?
+            },
+            None => (0..adapters.len())
+                .filter(|&index| missing_features(&adapters[index]).is_empty())
+                .min_by_key(|&index| type_rank(&adapters[index].info.device_type))
+                .ok_or_else(|| RenderError::Unsupported(format!(
+                    "no adapter supports the required features {:?}", required_features())))?,
+        };
+        info!("Using adapter {}: {}", index, adapters[index].info.name);
+
+        Ok(AdapterState::<B>::new_adapter(adapters.remove(index)))
     }
 
     pub(super) fn new_adapter(adapter: Adapter<B>) -> Self {
-        let memory_types = adapter.physical_device.memory_properties().memory_types;
         let limits = adapter.physical_device.limits();
         debug!("{:?}", limits);
         debug!("{:?}", adapter.physical_device.features());
 
         AdapterState {
-            adapter: Some(adapter),
-            memory_types,
-            limits
+            adapter,
+            limits,
+            max_samples: 64
+        }
+    }
+
+    pub(super) fn format_properties(
+        &self, format: Option<format::Format>
+    ) -> format::Properties {
+        self.adapter.physical_device.format_properties(format)
+    }
+
+    pub(super) fn optimal_depth_format(&self) -> Option<format::Format> {
+        let format_candidates = vec![format::Format::D32Sfloat,
+                                     format::Format::D32SfloatS8Uint,
+                                     format::Format::D24UnormS8Uint];
+        let reqs = format::ImageFeature::DEPTH_STENCIL_ATTACHMENT;
+        for format in format_candidates {
+            let props = self.format_properties(Some(format));
+            if props.optimal_tiling & reqs == reqs {
+                return Some(format)
+            }
         }
+        None
     }
 
     pub(super) fn get_max_usable_sample_count(&self) -> u8 {
@@ -34,12 +109,61 @@ impl<B: Backend> AdapterState<B> {
             self.limits.framebuffer_color_samples_count,
             self.limits.framebuffer_depth_samples_count
         );
-        if counts & 64 > 0 { return 64; }
-        if counts & 32 > 0 { return 32; }
-        if counts & 16 > 0 { return 16; }
-        if counts & 8 > 0 { return 8; }
-        if counts & 4 > 0 { return 4; }
-        if counts & 2 > 0 { return 2; }
-        return 1;
+        let mut samples = self.max_samples;
+        while samples > 1 && counts & samples == 0 {
+            samples >>= 1;
+        }
+        samples
+    }
+}
+
+/// One line per adapter, numbered for `AdapterPreference::Index`.
+pub(super) fn describe_adapters<B: Backend>(adapters: &[Adapter<B>]) -> Vec<String> {
+    adapters
+        .iter()
+        .enumerate()
+        .map(|(index, adapter)| {
+            let missing = missing_features(adapter);
+            let mut line = format!(
+                "{}: {} ({:?}, vendor {:#x}, device {:#x})",
+                index,
+                adapter.info.name,
+                adapter.info.device_type,
+                adapter.info.vendor,
+                adapter.info.device);
+            if !missing.is_empty() {
+                line.push_str(&format!(", unusable: missing {:?}", missing));
+            }
+            line
+        })
+        .collect()
+}
+
+fn missing_features<B: Backend>(adapter: &Adapter<B>) -> Features {
+    required_features() - adapter.physical_device.features()
+}
+
+fn matches<B: Backend>(preference: &AdapterPreference, index: usize, adapter: &Adapter<B>) -> bool {
+    match preference {
+        AdapterPreference::Index(wanted) => index == *wanted,
+        AdapterPreference::Type(kind) => adapter.info.device_type == match kind {
+            AdapterType::Discrete => DeviceType::DiscreteGpu,
+            AdapterType::Integrated => DeviceType::IntegratedGpu,
+            AdapterType::Virtual => DeviceType::VirtualGpu,
+            AdapterType::Cpu => DeviceType::Cpu,
+        },
+        AdapterPreference::Name(name) =>
+            adapter.info.name.to_lowercase().contains(&name.to_lowercase()),
+    }
+}
+
+/// Lower is preferred.
+fn type_rank(device_type: &DeviceType) -> u8 {
+    match device_type {
+        DeviceType::DiscreteGpu => 0,
+        DeviceType::IntegratedGpu => 1,
+        DeviceType::VirtualGpu => 2,
+        DeviceType::Cpu => 3,
+        DeviceType::Other => 4,
     }
 }
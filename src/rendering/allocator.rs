@@ -0,0 +1,362 @@
+use std::ops::Range;
+use gfx_hal::{Backend, Device, MemoryType, MemoryTypeId};
+use gfx_hal::memory::{Properties, Requirements};
+use log::{debug, warn};
+
+use super::error::RenderError;
+
+/// Size of the memory objects resources are sub-allocated from, unless
+/// the heap is too small for a few of them.
+const MAX_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+/// Blocks per heap at least, so a small heap isn't filled by one block.
+const MIN_BLOCKS_PER_HEAP: u64 = 8;
+
+/// A range of one of the allocator's memory objects, to be returned with
+/// `MemoryAllocator::free`.
+#[derive(Debug)]
+pub(super) struct Allocation {
+    pool: usize,
+    block: usize,
+    pub(super) offset: u64,
+    pub(super) size: u64,
+}
+
+/// How much device and host memory the renderer holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryStats {
+    /// Memory objects allocated from the driver.
+    pub blocks: usize,
+    /// Bytes in those memory objects.
+    pub reserved: u64,
+    pub allocations: usize,
+    /// Bytes handed out to resources.
+    pub used: u64,
+}
+
+struct Block<B: Backend> {
+    memory: B::Memory,
+    /// Host visible blocks stay mapped for as long as they exist, as a
+    /// memory object can't be mapped twice at once.
+    mapping: Option<*mut u8>,
+    ranges: FreeList,
+}
+
+/// Which ranges of a block are in use, kept apart from the memory so the
+/// bookkeeping can be tested without a device.
+#[derive(Debug)]
+struct FreeList {
+    size: u64,
+    /// Unused ranges, sorted by offset and never touching each other.
+    free: Vec<Range<u64>>,
+    allocations: usize,
+}
+
+impl FreeList {
+    fn new(size: u64) -> Self {
+        FreeList {
+            size,
+            free: vec![0..size],
+            allocations: 0,
+        }
+    }
+
+    /// Takes the lowest free range that fits, so a new block is filled
+    /// linearly and freed ranges are reused first.
+    fn allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let (index, offset) = self.free
+            .iter()
+            .enumerate()
+            .find_map(|(index, range)| {
+                let offset = align_up(range.start, alignment);
+                if offset + size <= range.end { Some((index, offset)) } else { None }
+            })?;
+
+        let range = self.free.remove(index);
+        if offset + size < range.end {
+            self.free.insert(index, offset + size..range.end);
+        }
+        if range.start < offset {
+            self.free.insert(index, range.start..offset);
+        }
+        self.allocations += 1;
+        Some(offset)
+    }
+
+    fn free(&mut self, range: Range<u64>) {
+        let mut index = self.free
+            .iter()
+            .position(|free| free.start > range.start)
+            .unwrap_or(self.free.len());
+        self.free.insert(index, range);
+
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            let next = self.free.remove(index + 1);
+            self.free[index].end = next.end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            let current = self.free.remove(index);
+            index -= 1;
+            self.free[index].end = current.end;
+        }
+        self.allocations -= 1;
+    }
+
+    fn used(&self) -> u64 {
+        self.size - self.free.iter().map(|range| range.end - range.start).sum::<u64>()
+    }
+}
+
+/// The blocks of one memory type. Freed blocks leave a `None` behind so
+/// the indices held by allocations stay valid.
+struct Pool<B: Backend> {
+    memory_type: MemoryTypeId,
+    block_size: u64,
+    blocks: Vec<Option<Block<B>>>,
+}
+
+/// Hands out ranges of a few large memory objects instead of allocating
+/// one per resource, as drivers limit how many can exist. Resources of
+/// at least half a block get a block of their own. Host visible memory
+/// is mapped once per block, see `mapping`.
+pub(super) struct MemoryAllocator<B: Backend> {
+    memory_types: Vec<MemoryType>,
+    /// Two per memory type: buffers and linear images in the first,
+    /// optimally tiled images in the second, so the two never share a
+    /// block and `buffer_image_granularity` doesn't apply.
+    pools: Vec<Pool<B>>,
+}
+
+impl<B: Backend> MemoryAllocator<B> {
+    pub(super) fn new(memory_types: Vec<MemoryType>, heap_sizes: &[u64]) -> Self {
+        let pools = memory_types
+            .iter()
+            .enumerate()
+            .flat_map(|(id, memory_type)| {
+                let block_size = std::cmp::min(
+                    MAX_BLOCK_SIZE,
+                    heap_sizes[memory_type.heap_index] / MIN_BLOCKS_PER_HEAP);
+                (0..2).map(move |_| Pool {
+                    memory_type: id.into(),
+                    block_size,
+                    blocks: vec![],
+                })
+            })
+            .collect();
+
+        MemoryAllocator {
+            memory_types,
+            pools
+        }
+    }
+
+    /// `linear` is true for buffers and linearly tiled images.
+    pub(super) unsafe fn allocate(
+        &mut self,
+        device: &B::Device,
+        requirements: Requirements,
+        properties: Properties,
+        linear: bool
+    ) -> Result<Allocation, RenderError> {
+        let memory_type = self.find_memory_type(requirements.type_mask, properties)?;
+        let pool_index = memory_type * 2 + if linear { 0 } else { 1 };
+        let pool = &mut self.pools[pool_index];
+        let alignment = std::cmp::max(requirements.alignment, 1);
+
+        let dedicated = requirements.size >= pool.block_size / 2;
+        if !dedicated {
+            let found = pool.blocks
+                .iter_mut()
+                .enumerate()
+                .find_map(|(index, block)| {
+                    block.as_mut()
+                        .and_then(|block| block.ranges.allocate(requirements.size, alignment))
+                        .map(|offset| (index, offset))
+                });
+            if let Some((block, offset)) = found {
+                return Ok(Allocation {
+                    pool: pool_index,
+                    block,
+                    offset,
+                    size: requirements.size
+                });
+            }
+        }
+
+        let block_size = if dedicated { requirements.size } else { pool.block_size };
+        debug!("Allocating {} byte block of memory type {:?}", block_size, pool.memory_type);
+        let memory = device.allocate_memory(pool.memory_type, block_size)?;
+        let mapping = if self.memory_types[memory_type].properties.contains(Properties::CPU_VISIBLE) {
+            match device.map_memory(&memory, 0..block_size) {
+                Ok(mapping) => Some(mapping),
+                Err(e) => {
+                    device.free_memory(memory);
+                    return Err(e.into());
+                }
+            }
+        } else {
+            None
+        };
+        let mut block = Block {
+            memory,
+            mapping,
+            ranges: FreeList::new(block_size),
+        };
+        let offset = block.ranges.allocate(requirements.size, alignment).unwrap();
+
+        let index = match pool.blocks.iter().position(|block| block.is_none()) {
+            Some(index) => {
+                pool.blocks[index] = Some(block);
+                index
+            },
+            None => {
+                pool.blocks.push(Some(block));
+                pool.blocks.len() - 1
+            }
+        };
+
+        Ok(Allocation {
+            pool: pool_index,
+            block: index,
+            offset,
+            size: requirements.size
+        })
+    }
+
+    /// Empty blocks are given back to the driver straight away.
+    pub(super) unsafe fn free(&mut self, device: &B::Device, allocation: Allocation) {
+        let slot = &mut self.pools[allocation.pool].blocks[allocation.block];
+        let empty = {
+            let block = slot.as_mut().unwrap();
+            block.ranges.free(allocation.offset..allocation.offset + allocation.size);
+            block.ranges.allocations == 0
+        };
+        if empty {
+            free_block(device, slot.take().unwrap());
+        }
+    }
+
+    pub(super) fn memory(&self, allocation: &Allocation) -> &B::Memory {
+        &self.block(allocation).memory
+    }
+
+    /// Where the allocation can be written and read on the host, if its
+    /// memory is host visible.
+    pub(super) fn mapping(&self, allocation: &Allocation) -> Option<*mut u8> {
+        self.block(allocation)
+            .mapping
+            .map(|mapping| mapping.wrapping_add(allocation.offset as usize))
+    }
+
+    fn block(&self, allocation: &Allocation) -> &Block<B> {
+        self.pools[allocation.pool].blocks[allocation.block].as_ref().unwrap()
+    }
+
+    pub(super) fn stats(&self) -> MemoryStats {
+        self.pools
+            .iter()
+            .flat_map(|pool| pool.blocks.iter().flatten())
+            .fold(MemoryStats::default(), |stats, block| MemoryStats {
+                blocks: stats.blocks + 1,
+                reserved: stats.reserved + block.ranges.size,
+                allocations: stats.allocations + block.ranges.allocations,
+                used: stats.used + block.ranges.used(),
+            })
+    }
+
+    /// Frees every block, whether or not everything was returned.
+    pub(super) unsafe fn dispose(&mut self, device: &B::Device) {
+        let stats = self.stats();
+        if stats.allocations != 0 {
+            warn!("{} allocations still in use on shutdown", stats.allocations);
+        }
+        for pool in &mut self.pools {
+            for block in pool.blocks.drain(..).flatten() {
+                free_block(device, block);
+            }
+        }
+    }
+
+    /// First memory type allowed by `type_mask` that has all `properties`.
+    fn find_memory_type(&self, type_mask: u64, properties: Properties) -> Result<usize, RenderError> {
+        self.memory_types
+            .iter()
+            .enumerate()
+            .position(|(id, memory_type)| {
+                type_mask & (1 << id) != 0 && memory_type.properties.contains(properties)
+            })
+            .ok_or(RenderError::NoMemoryType(properties))
+    }
+}
+
+unsafe fn free_block<B: Backend>(device: &B::Device, block: Block<B>) {
+    if block.mapping.is_some() {
+        device.unmap_memory(&block.memory);
+    }
+    device.free_memory(block.memory);
+}
+
+pub(super) fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FreeList;
+
+    #[test]
+    fn aligned_allocation_leaves_a_head_gap() {
+        let mut ranges = FreeList::new(256);
+        assert_eq!(ranges.allocate(10, 1), Some(0));
+        assert_eq!(ranges.allocate(16, 64), Some(64));
+        assert_eq!(ranges.free, vec![10..64, 80..256]);
+        assert_eq!(ranges.used(), 26);
+
+        // the gap is still used by allocations that fit
+        assert_eq!(ranges.allocate(8, 8), Some(16));
+        assert_eq!(ranges.free, vec![10..16, 24..64, 80..256]);
+    }
+
+    #[test]
+    fn freeing_a_middle_range() {
+        let mut ranges = FreeList::new(100);
+        for offset in &[0, 10, 20] {
+            assert_eq!(ranges.allocate(10, 1), Some(*offset));
+        }
+
+        ranges.free(10..20);
+        assert_eq!(ranges.free, vec![10..20, 30..100]);
+        assert_eq!(ranges.allocations, 2);
+        assert_eq!(ranges.used(), 20);
+    }
+
+    #[test]
+    fn freeing_coalesces_with_both_neighbours() {
+        let mut ranges = FreeList::new(40);
+        for offset in &[0, 10, 20, 30] {
+            assert_eq!(ranges.allocate(10, 1), Some(*offset));
+        }
+
+        ranges.free(0..10);
+        ranges.free(20..30);
+        assert_eq!(ranges.free, vec![0..10, 20..30]);
+        ranges.free(10..20);
+        assert_eq!(ranges.free, vec![0..30]);
+        ranges.free(30..40);
+        assert_eq!(ranges.free, vec![0..40]);
+        assert_eq!(ranges.allocations, 0);
+        assert_eq!(ranges.used(), 0);
+    }
+
+    #[test]
+    fn freed_ranges_are_reused_first() {
+        let mut ranges = FreeList::new(100);
+        assert_eq!(ranges.allocate(30, 1), Some(0));
+        assert_eq!(ranges.allocate(30, 1), Some(30));
+
+        ranges.free(0..30);
+        assert_eq!(ranges.allocate(20, 1), Some(0));
+        assert_eq!(ranges.allocate(20, 1), Some(60));
+        assert_eq!(ranges.free, vec![20..30, 80..100]);
+        assert_eq!(ranges.allocate(30, 1), None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use log::debug;
+use image;
+use serde::Deserialize;
+
+use super::pipeline_desc::PipelineDesc;
+use super::primitives::{Mesh, Model, TextureSource};
+
+/// Where the viewer reads its models and texture from. Models are read as
+/// glTF for `.gltf`/`.glb` files and as OBJ otherwise. The texture is
+/// used for meshes that have no material, and only read if there are any.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssetPaths {
+    pub models: Vec<PathBuf>,
+    pub texture: PathBuf,
+}
+
+impl Default for AssetPaths {
+    fn default() -> Self {
+        AssetPaths {
+            models: vec![PathBuf::from("models/chalet.obj")],
+            texture: PathBuf::from("textures/chalet.jpg"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AssetError {
+    Missing(PathBuf),
+    Model(PathBuf, String),
+    Texture(PathBuf, image::ImageError),
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssetError::Missing(path) =>
+                write!(f, "asset file not found: {}", path.display()),
+            AssetError::Model(path, e) =>
+                write!(f, "failed to load model {}: {}", path.display(), e),
+            AssetError::Texture(path, e) =>
+                write!(f, "failed to load texture {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
+
+/// CPU side copies of everything the renderer uploads to the GPU.
+pub struct Assets {
+    pub(super) models: Vec<Model>,
+    /// The material textures and the default texture.
+    pub(super) textures: Vec<image::RgbaImage>,
+    /// Index into `textures` for each material of each model.
+    material_textures: Vec<Vec<usize>>,
+    /// Index into `textures` for meshes without a material.
+    default_texture: usize,
+    /// Each distinct pipeline the materials ask for, the default one, used
+    /// for meshes without a material, first.
+    pub(super) pipelines: Vec<PipelineDesc>,
+    /// Index into `pipelines` for each material of each model.
+    material_pipelines: Vec<Vec<usize>>,
+}
+
+impl Assets {
+    pub fn load(paths: &AssetPaths) -> Result<Self, AssetError> {
+        let mut textures = Vec::new();
+        let mut file_textures: HashMap<PathBuf, usize> = HashMap::new();
+        let mut white_texture = None;
+        let mut models = Vec::with_capacity(paths.models.len());
+        let mut material_textures = Vec::with_capacity(paths.models.len());
+        let mut pipelines = vec![PipelineDesc::default()];
+        let mut pipeline_indices: HashMap<PipelineDesc, usize> = HashMap::new();
+        pipeline_indices.insert(PipelineDesc::default(), 0);
+        let mut material_pipelines = Vec::with_capacity(paths.models.len());
+
+        for path in &paths.models {
+            let mut model = load_model(path)?;
+
+            let mut embedded_images: Vec<Option<image::RgbaImage>> =
+                std::mem::replace(&mut model.images, vec![])
+                    .into_iter()
+                    .map(Some)
+                    .collect();
+            let mut embedded_textures: HashMap<usize, usize> = HashMap::new();
+            let mut model_textures = Vec::with_capacity(model.materials.len());
+            let mut model_pipelines = Vec::with_capacity(model.materials.len());
+
+            for material in &model.materials {
+                let index = match &material.diffuse_texture {
+                    Some(TextureSource::File(path)) => match file_textures.get(path).cloned() {
+                        Some(index) => index,
+                        None => {
+                            textures.push(load_texture(path)?);
+                            file_textures.insert(path.clone(), textures.len() - 1);
+                            textures.len() - 1
+                        }
+                    },
+                    Some(TextureSource::Embedded(image)) => *embedded_textures
+                        .entry(*image)
+                        .or_insert_with(|| {
+                            textures.push(embedded_images[*image].take().unwrap());
+                            textures.len() - 1
+                        }),
+                    // untextured materials only show their diffuse color
+                    None => white(&mut textures, &mut white_texture),
+                };
+                debug!("Material {:?} uses texture {}", material.name, index);
+                model_textures.push(index);
+
+                let pipeline = *pipeline_indices
+                    .entry(material.pipeline.clone())
+                    .or_insert_with(|| {
+                        pipelines.push(material.pipeline.clone());
+                        pipelines.len() - 1
+                    });
+                model_pipelines.push(pipeline);
+            }
+
+            models.push(model);
+            material_textures.push(model_textures);
+            material_pipelines.push(model_pipelines);
+        }
+
+        // the default texture is only read when something is drawn with it
+        let needs_default = models
+            .iter()
+            .any(|model| model.meshes.iter().any(|mesh| mesh.material.is_none()));
+        let default_texture = if needs_default {
+            textures.push(load_texture(&paths.texture)?);
+            textures.len() - 1
+        } else {
+            white(&mut textures, &mut white_texture)
+        };
+
+        Ok(Assets {
+            models,
+            textures,
+            material_textures,
+            default_texture,
+            pipelines,
+            material_pipelines
+        })
+    }
+
+    /// Index into `textures` of the texture a mesh of `model` is drawn with.
+    pub(super) fn mesh_texture(&self, model: usize, mesh: &Mesh) -> usize {
+        mesh.material
+            .map(|material| self.material_textures[model][material])
+            .unwrap_or(self.default_texture)
+    }
+
+    /// Index into `pipelines` of the pipeline a mesh of `model` is drawn with.
+    pub(super) fn mesh_pipeline(&self, model: usize, mesh: &Mesh) -> usize {
+        mesh.material
+            .map(|material| self.material_pipelines[model][material])
+            .unwrap_or(0)
+    }
+}
+
+/// Index of a 1x1 white texture, added to `textures` the first time.
+fn white(
+    textures: &mut Vec<image::RgbaImage>,
+    white_texture: &mut Option<usize>
+) -> usize {
+    *white_texture.get_or_insert_with(|| {
+        textures.push(image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255])));
+        textures.len() - 1
+    })
+}
+
+fn load_model(path: &Path) -> Result<Model, AssetError> {
+    let model_path = check_exists(path)?;
+    let is_gltf = match model_path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("gltf") || ext.eq_ignore_ascii_case("glb"),
+        None => false,
+    };
+    let model = if is_gltf {
+        Model::load_gltf(model_path).map_err(|e| e.to_string())
+    } else {
+        Model::load(model_path).map_err(|e| e.to_string())
+    };
+    model.map_err(|e| AssetError::Model(path.to_path_buf(), e))
+}
+
+fn load_texture(path: &Path) -> Result<image::RgbaImage, AssetError> {
+    debug!("Reading texture file: {:?}", path);
+    Ok(image::open(check_exists(path)?)
+        .map_err(|e| AssetError::Texture(path.to_path_buf(), e))?
+        .to_rgba())
+}
+
+fn check_exists(path: &Path) -> Result<&Path, AssetError> {
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(AssetError::Missing(path.to_path_buf()))
+    }
+}
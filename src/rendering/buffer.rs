@@ -1,18 +1,22 @@
 use std::cell::RefCell;
 use std::rc::Rc;
-use gfx_hal::{Backend, CommandPool, Device, IndexType, MemoryType, Graphics};
-use gfx_hal::buffer::Usage;
-use gfx_hal::command;
+use gfx_hal::{Backend, Device, IndexType};
+use gfx_hal::buffer::{Access, Usage};
 use gfx_hal::memory::Properties;
 use gfx_hal::pso;
+use gfx_hal::pso::PipelineStage;
 use image;
 
+use super::allocator::Allocation;
 use super::device::DeviceState;
 use super::descriptors::DescriptorSet;
 use super::adapter::AdapterState;
+use super::error::RenderError;
+use super::primitives::{Model, Vertex};
+use super::upload::UploadManager;
 
 pub(super) struct BufferState<B: Backend> {
-    memory: Option<B::Memory>,
+    memory: Option<Allocation>,
     buffer: Option<B::Buffer>,
     device: Rc<RefCell<DeviceState<B>>>,
     size: u64,
@@ -23,72 +27,83 @@ impl <B: Backend> BufferState<B> {
         self.buffer.as_ref().unwrap()
     }
 
-    unsafe fn new<T>(
+    pub(super) unsafe fn new<T>(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
         buffer_size: u64,
         usage: Usage,
         properties: Properties,
-        memory_types: &[MemoryType],
-    ) -> Self
+    ) -> Result<Self, RenderError>
     where T: Copy
     {
         let (memory, buffer, size) = {
-            let device = &device_ptr.borrow().device;
-            let mut buffer = device.create_buffer(buffer_size, usage).unwrap();
-            let mem_req = device.get_buffer_requirements(&buffer);
+            let mut device = device_ptr.borrow_mut();
+            let mut buffer = device.device.create_buffer(buffer_size, usage)?;
+            let mem_req = device.device.get_buffer_requirements(&buffer);
             let size = mem_req.size;
 
-            let memory_type = memory_types
-                .iter()
-                .enumerate()
-                .position(|(id, mem_type)| {
-                    mem_req.type_mask & (1 << id) != 0 &&
-                    mem_type.properties & properties == properties
-                })
-                .unwrap()
-                .into();
-
-            let memory = device.allocate_memory(
-                memory_type, size).unwrap();
-            device.bind_buffer_memory(&memory, 0, &mut buffer).unwrap();
+            let memory = device
+                .allocate(mem_req, properties, true)
+                .and_then(|memory| {
+                    let bound = device.device.bind_buffer_memory(
+                        device.memory(&memory), memory.offset, &mut buffer);
+                    match bound {
+                        Ok(()) => Ok(memory),
+                        Err(e) => {
+                            device.free(memory);
+                            Err(e.into())
+                        }
+                    }
+                });
+            let memory = match memory {
+                Ok(memory) => memory,
+                Err(e) => {
+                    device.device.destroy_buffer(buffer);
+                    return Err(e);
+                }
+            };
 
             (memory, buffer, size)
         };
 
-        BufferState {
+        Ok(BufferState {
             memory: Some(memory),
             buffer: Some(buffer),
             device: device_ptr,
             size
-        }
+        })
+    }
+
+    /// Start of the buffer's memory on the host, if it is host visible.
+    pub(super) fn mapping(&self) -> Option<*mut u8> {
+        self.device.borrow().mapping(self.memory.as_ref().unwrap())
     }
 
-    fn update_data<T>(&mut self, offset: u64, data_source: &[T]) where T: Copy {
-        let device = &self.device.borrow().device;
+    fn update_data<T>(&mut self, offset: u64, data_source: &[T]) -> Result<(), RenderError>
+        where T: Copy
+    {
         let stride = std::mem::size_of::<T>() as u64;
         let upload_size = data_source.len() as u64 * stride;
 
         assert!(offset + upload_size <= self.size);
 
-        let memory = self.memory.as_mut().unwrap();
+        let mapping = self.mapping()
+            .ok_or_else(|| RenderError::Backend("buffer memory is not host visible".to_string()))?;
         unsafe {
-            let mut data_target = device
-                .acquire_mapping_writer::<T>(
-                    &memory, offset..self.size)
-                .unwrap();
-            data_target[0..data_source.len()].copy_from_slice(
-                data_source);
-            device.release_mapping_writer(data_target).unwrap();
+            std::ptr::copy_nonoverlapping(
+                data_source.as_ptr() as *const u8,
+                mapping.add(offset as usize),
+                upload_size as usize);
         }
+        Ok(())
     }
 }
 
 impl<B: Backend> Drop for BufferState<B> {
     fn drop(&mut self) {
-        let device = &self.device.borrow().device;
+        let mut device = self.device.borrow_mut();
         unsafe {
-            device.destroy_buffer(self.buffer.take().unwrap());
-            device.free_memory(self.memory.take().unwrap());
+            device.device.destroy_buffer(self.buffer.take().unwrap());
+            device.free(self.memory.take().unwrap());
         }
     }
 }
@@ -97,41 +112,29 @@ impl<B: Backend> Drop for BufferState<B> {
 pub(super) struct VertexBuffer<B: Backend>(BufferState<B>);
 
 impl <B: Backend> VertexBuffer<B> {
+    /// The data is copied when `uploads` is flushed.
     pub(super) unsafe fn new<T>(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
-        command_pool: &mut CommandPool<B, Graphics>,
+        uploads: &mut UploadManager<B>,
         data_source: &[T],
-        memory_types: &[MemoryType],
-    ) -> Self where T: Copy {
+    ) -> Result<Self, RenderError> where T: Copy {
         let stride = std::mem::size_of::<T>() as u64;
         let buffer_size = data_source.len() as u64 * stride;
 
-        let mut staging_buffer = BufferState::new::<T>(
-            Rc::clone(&device_ptr),
-            buffer_size,
-            Usage::TRANSFER_SRC,
-            Properties::CPU_VISIBLE | Properties::COHERENT,
-            memory_types
-        );
-        staging_buffer.update_data(0, data_source);
-
         let vertex_buffer = BufferState::new::<T>(
             Rc::clone(&device_ptr),
             buffer_size,
             Usage::TRANSFER_DST | Usage::VERTEX,
-            Properties::DEVICE_LOCAL,
-            memory_types
-        );
-
-        copy_command_buffer(
-            &device_ptr,
-            command_pool,
-            staging_buffer.get_buffer(),
+            Properties::DEVICE_LOCAL
+        )?;
+        uploads.upload_buffer(
+            data_source,
             vertex_buffer.get_buffer(),
-            buffer_size
-        );
+            PipelineStage::VERTEX_INPUT,
+            Access::VERTEX_BUFFER_READ
+        )?;
 
-        VertexBuffer(vertex_buffer)
+        Ok(VertexBuffer(vertex_buffer))
     }
 
     pub(super) fn get_buffer(&self) -> &B::Buffer {
@@ -142,41 +145,29 @@ impl <B: Backend> VertexBuffer<B> {
 pub(super) struct IndexBuffer<B: Backend>(BufferState<B>, IndexType);
 
 impl <B: Backend> IndexBuffer<B> {
+    /// The data is copied when `uploads` is flushed.
     pub(super) unsafe fn new(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
-        command_pool: &mut CommandPool<B, Graphics>,
+        uploads: &mut UploadManager<B>,
         data_source: &[u32],
-        memory_types: &[MemoryType],
-    ) -> Self {
+    ) -> Result<Self, RenderError> {
         let stride = std::mem::size_of::<u32>() as u64;
         let buffer_size = data_source.len() as u64 * stride;
 
-        let mut staging_buffer = BufferState::new::<u32>(
-            Rc::clone(&device_ptr),
-            buffer_size,
-            Usage::TRANSFER_SRC,
-            Properties::CPU_VISIBLE | Properties::COHERENT,
-            memory_types
-        );
-        staging_buffer.update_data(0, data_source);
-
         let index_buffer = BufferState::new::<u32>(
             Rc::clone(&device_ptr),
             buffer_size,
             Usage::TRANSFER_DST | Usage::INDEX,
-            Properties::DEVICE_LOCAL,
-            memory_types
-        );
-
-        copy_command_buffer(
-            &device_ptr,
-            command_pool,
-            staging_buffer.get_buffer(),
+            Properties::DEVICE_LOCAL
+        )?;
+        uploads.upload_buffer(
+            data_source,
             index_buffer.get_buffer(),
-            buffer_size
-        );
+            PipelineStage::VERTEX_INPUT,
+            Access::INDEX_BUFFER_READ
+        )?;
 
-        IndexBuffer(index_buffer, IndexType::U32)
+        Ok(IndexBuffer(index_buffer, IndexType::U32))
     }
 
     pub(super) fn get_buffer(&self) -> &B::Buffer {
@@ -189,24 +180,53 @@ impl <B: Backend> IndexBuffer<B> {
     }
 }
 
+/// The vertex and index buffers of a single model.
+pub(super) struct ModelBuffers<B: Backend> {
+    pub(super) vertex_buffer: VertexBuffer<B>,
+    pub(super) index_buffer: IndexBuffer<B>,
+}
+
+impl <B: Backend> ModelBuffers<B> {
+    pub(super) unsafe fn new(
+        device_ptr: Rc<RefCell<DeviceState<B>>>,
+        uploads: &mut UploadManager<B>,
+        model: &Model,
+    ) -> Result<Self, RenderError> {
+        let vertex_buffer = VertexBuffer::new::<Vertex>(
+            Rc::clone(&device_ptr),
+            uploads,
+            &model.vertices
+        )?;
+
+        let index_buffer = IndexBuffer::new(
+            Rc::clone(&device_ptr),
+            uploads,
+            &model.indicies
+        )?;
+
+        Ok(ModelBuffers {
+            vertex_buffer,
+            index_buffer
+        })
+    }
+}
+
 pub(super) struct UniformBuffer<B: Backend>(BufferState<B>, DescriptorSet<B>);
 
 impl <B: Backend> UniformBuffer<B> {
     pub(super) unsafe fn new<T>(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
-        memory_types: &[MemoryType],
         desc: DescriptorSet<B>,
         binding: u32
-    ) -> Self where T: Copy {
+    ) -> Result<Self, RenderError> where T: Copy {
         let buffer_size = std::mem::size_of::<T>() as u64;
 
         let buffer = BufferState::new::<T>(
             Rc::clone(&device_ptr),
             buffer_size,
             Usage::UNIFORM,
-            Properties::CPU_VISIBLE | Properties::COHERENT,
-            memory_types
-        );
+            Properties::CPU_VISIBLE | Properties::COHERENT
+        )?;
 
         let device = &device_ptr.borrow().device;
 
@@ -224,11 +244,13 @@ impl <B: Backend> UniformBuffer<B> {
 
         device.write_descriptor_sets(write);
 
-        UniformBuffer(buffer, desc)
+        Ok(UniformBuffer(buffer, desc))
     }
 
-    pub fn update_data<T>(&mut self, offset: u64, data_source: &[T]) where T: Copy {
-        self.0.update_data(offset, data_source);
+    pub fn update_data<T>(&mut self, offset: u64, data_source: &[T]) -> Result<(), RenderError>
+        where T: Copy
+    {
+        self.0.update_data(offset, data_source)
     }
 
     pub(super) fn get_descriptor_set(&self) -> &B::DescriptorSet {
@@ -237,80 +259,63 @@ impl <B: Backend> UniformBuffer<B> {
 }
 
 
-pub(super) struct TextureBuffer<B: Backend>(BufferState<B>);
+pub(super) struct ReadbackBuffer<B: Backend> {
+    buffer: BufferState<B>,
+    pub(super) width: u32,
+    pub(super) height: u32,
+    pub(super) row_pitch: u32,
+}
+
+impl <B: Backend> ReadbackBuffer<B> {
+    pub(super) const STRIDE: u32 = 4;
 
-impl <B: Backend> TextureBuffer<B> {
     pub(super) unsafe fn new(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
         adapter: &AdapterState<B>,
-        img: &image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
-        usage: Usage
-    ) -> (Self, u32, u32, u32, usize) {
-        let (width, height) = img.dimensions();
-
-        let row_alignment_mask = adapter.limits.optimal_buffer_copy_pitch_alignment as u32 - 1;
-        let stride = 4usize;
-
-        let row_pitch = (width * stride as u32 + row_alignment_mask) & !row_alignment_mask;
-        let upload_size = (height * row_pitch) as u64;
-
-
-        let mut buffer = BufferState::new::<u8>(
+        width: u32,
+        height: u32
+    ) -> Result<Self, RenderError> {
+        // a limit of 0 means rows need no alignment
+        let row_alignment_mask = std::cmp::max(
+            adapter.limits.optimal_buffer_copy_pitch_alignment as u32, 1) - 1;
+        let row_pitch = (width * Self::STRIDE + row_alignment_mask) & !row_alignment_mask;
+        let download_size = (height * row_pitch) as u64;
+
+        let buffer = BufferState::new::<u8>(
             Rc::clone(&device_ptr),
-            upload_size as _,
-            usage,
-            Properties::CPU_VISIBLE | Properties::COHERENT,
-            &adapter.memory_types);
-
-        {
-            let device = &device_ptr.borrow().device;
-            let memory = buffer.memory.as_mut().unwrap();
-            let size = buffer.size;
-            let mut data_target = device
-                    .acquire_mapping_writer::<u8>(memory, 0..size)
-                    .unwrap();
-
-            for y in 0..height as usize {
-                let data_source_slice = &(**img)
-                    [y * (width as usize) * stride..(y + 1) * (width as usize) * stride];
-                let dest_base = y * row_pitch as usize;
-                data_target[dest_base..dest_base + data_source_slice.len()]
-                        .copy_from_slice(data_source_slice);
-            }
-
-            device.release_mapping_writer(data_target).unwrap();
-        }
-
-        (TextureBuffer(buffer), width, height, row_pitch, stride)
+            download_size,
+            Usage::TRANSFER_DST,
+            Properties::CPU_VISIBLE | Properties::COHERENT)?;
+
+        Ok(ReadbackBuffer {
+            buffer,
+            width,
+            height,
+            row_pitch
+        })
     }
 
     pub(super) fn get_buffer(&self) -> &B::Buffer {
-        self.0.get_buffer()
+        self.buffer.get_buffer()
     }
-}
 
+    /// Copies the mapped buffer contents into a tightly packed image,
+    /// dropping the row padding required by the copy alignment.
+    pub(super) fn read_image(&self) -> Result<image::RgbaImage, RenderError> {
+        let mapping = self.buffer.mapping()
+            .ok_or_else(|| RenderError::Backend("buffer memory is not host visible".to_string()))?;
+        let row_size = (self.width * Self::STRIDE) as usize;
+        let mut pixels = Vec::with_capacity(row_size * self.height as usize);
 
-unsafe fn copy_command_buffer<B>(
-    device_ptr: &Rc<RefCell<DeviceState<B>>>,
-    command_pool: &mut CommandPool<B, Graphics>,
-    src_buffer: &B::Buffer,
-    dst_buffer: &B::Buffer,
-    size: u64
-) where B: Backend {
-
-    let mut cmd_buffer: command::CommandBuffer<B, gfx_hal::Graphics, command::OneShot> = {
-        command_pool.acquire_command_buffer::<command::OneShot>()
-    };
-    cmd_buffer.begin();
-    cmd_buffer.copy_buffer(src_buffer, dst_buffer, &[command::BufferCopy {
-        src: 0,
-        dst: 0,
-        size: size
-    }]);
-    cmd_buffer.finish();
-
-    let queue = &mut device_ptr.borrow_mut().queues.queues[0];
-    queue.submit_without_semaphores(std::iter::once(&cmd_buffer), None);
-    queue.wait_idle().unwrap();
-    // explicit cmd_buffer free on Drop
+        let data_source = unsafe {
+            std::slice::from_raw_parts(mapping as *const u8, self.buffer.size as usize)
+        };
+        for y in 0..self.height as usize {
+            let src_base = y * self.row_pitch as usize;
+            pixels.extend_from_slice(&data_source[src_base..src_base + row_size]);
+        }
+
+        Ok(image::ImageBuffer::from_raw(self.width, self.height, pixels).unwrap())
+    }
 }
+
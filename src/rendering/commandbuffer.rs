@@ -1,138 +1,198 @@
 use std::cell::RefCell;
+use std::ops::Range;
 use std::rc::Rc;
-use gfx_hal::{Backend, Device};
+use gfx_hal::{Backend, CommandPool, Device, Graphics};
 use gfx_hal::command;
 use gfx_hal::pool;
 use gfx_hal::pso;
 use gfx_hal::buffer::IndexBufferView;
+use glm::Mat4;
 
 use super::device::DeviceState;
-use super::framebuffer::FramebufferState;
+use super::error::RenderError;
 use super::render_pass::RenderPassState;
-use super::buffer::{IndexBuffer, VertexBuffer, UniformBuffer};
-use super::swapchain::SwapchainState;
+use super::buffer::{IndexBuffer, VertexBuffer};
 use super::pipeline::PipelineState;
 
-pub(super) const MAX_FRAMES_IN_FLIGHT: usize = 2;
+use crate::config::RenderingConfig;
+
+/// One `draw_indexed` call: a range of a model's index buffer, the
+/// pipeline and descriptor set of the material it is drawn with and the
+/// object's model matrix, which is passed as a push constant.
+pub(super) struct DrawCall<'a, B: Backend> {
+    pub(super) pipeline: &'a PipelineState<B>,
+    pub(super) vertex_buffer: &'a VertexBuffer<B>,
+    pub(super) index_buffer: &'a IndexBuffer<B>,
+    pub(super) indicies: Range<u32>,
+    pub(super) material_set: &'a B::DescriptorSet,
+    pub(super) transform: Mat4,
+}
 
+/// One command buffer per frame in flight, recorded again every frame
+/// from that frame's draw calls. Each has a pool of its own, reset once
+/// the frame's fence shows the previous recording has executed.
 pub(super) struct CommandBufferState<B: Backend> {
     device: Rc<RefCell<DeviceState<B>>>,
-    command_pool: Option<gfx_hal::CommandPool<B, gfx_hal::Graphics>>,
-    pub(super) command_buffers: Option<Vec<command::CommandBuffer<B, gfx_hal::Graphics, command::MultiShot>>>,
+    command_pools: Option<Vec<CommandPool<B, Graphics>>>,
+    pub(super) command_buffers: Option<Vec<command::CommandBuffer<B, Graphics, command::OneShot>>>,
     pub(super) acquire_semaphores: Option<Vec<B::Semaphore>>,
     pub(super) present_semaphores: Option<Vec<B::Semaphore>>,
-    pub(super) fences: Option<Vec<B::Fence>>
+    pub(super) fences: Option<Vec<B::Fence>>,
+    /// The frame, and so the fence, that last rendered to each swapchain
+    /// image. There can be more images than frames in flight.
+    images_in_flight: Vec<Option<usize>>,
 }
 
 impl<B: Backend> CommandBufferState<B> {
     pub(super) unsafe fn new(
         device: Rc<RefCell<DeviceState<B>>>,
-        framebuffer_state: &mut FramebufferState<B>,
-        render_pass: &RenderPassState<B>,
-        swapchain: &SwapchainState<B>,
-        pipeline: &PipelineState<B>,
-        vertex_buffer: &VertexBuffer<B>,
-        index_buffer: &IndexBuffer<B>,
-        index_count: u32,
-        uniform_buffers: &Vec<UniformBuffer<B>>
-    ) -> Self {
-        let frame_images = framebuffer_state.frame_images
-            .as_ref().unwrap();
-        let framebuffers = framebuffer_state.framebuffers
-            .as_ref().unwrap();
-        let iter_count = if frame_images.len() != 0 {
-            frame_images.len()
-        } else {
-            1 // GL can have zero
-        };
-
-        //let mut command_pools: Vec<gfx_hal::CommandPool<B, gfx_hal::Graphics>> = vec![];
-        let mut command_pool = device
-            .borrow()
-            .device
-            .create_command_pool_typed(
+        config: &RenderingConfig
+    ) -> Result<Self, RenderError> {
+        let mut command_pools: Vec<CommandPool<B, Graphics>> = (0..config.frames_in_flight)
+            .map(|_| device.borrow().device.create_command_pool_typed(
                 &device.borrow().queues,
-                pool::CommandPoolCreateFlags::empty(),
-            )
-            .expect("Can't create command pool");
-
-        let acquire_semaphores: Vec<B::Semaphore> = (0..MAX_FRAMES_IN_FLIGHT)
-            .map(|_| device.borrow().device.create_semaphore().unwrap()).collect();
-        let present_semaphores: Vec<B::Semaphore> = (0..MAX_FRAMES_IN_FLIGHT)
-            .map(|_| device.borrow().device.create_semaphore().unwrap()).collect();
-        let fences: Vec<B::Fence> = (0..MAX_FRAMES_IN_FLIGHT)
-            .map(|_| device.borrow().device.create_fence(true).unwrap()).collect();
-
-        let mut command_buffers: Vec<command::CommandBuffer<B, gfx_hal::Graphics, command::MultiShot>> = vec![];
-        for _ in 0..iter_count {
-            let cmd_buffer = command_pool.acquire_command_buffer::<command::MultiShot>();
-            command_buffers.push(cmd_buffer);
-        }
+                pool::CommandPoolCreateFlags::TRANSIENT,
+            ))
+            .collect::<Result<_, _>>()?;
+
+        let acquire_semaphores: Vec<B::Semaphore> = (0..config.frames_in_flight)
+            .map(|_| device.borrow().device.create_semaphore())
+            .collect::<Result<_, _>>()?;
+        let present_semaphores: Vec<B::Semaphore> = (0..config.frames_in_flight)
+            .map(|_| device.borrow().device.create_semaphore())
+            .collect::<Result<_, _>>()?;
+        let fences: Vec<B::Fence> = (0..config.frames_in_flight)
+            .map(|_| device.borrow().device.create_fence(true))
+            .collect::<Result<_, _>>()?;
+
+        let command_buffers = command_pools
+            .iter_mut()
+            .map(|command_pool| command_pool.acquire_command_buffer::<command::OneShot>())
+            .collect();
+
+        Ok(CommandBufferState {
+            command_pools: Some(command_pools),
+            command_buffers: Some(command_buffers),
+            acquire_semaphores: Some(acquire_semaphores),
+            present_semaphores: Some(present_semaphores),
+            fences: Some(fences),
+            images_in_flight: vec![],
+            device
+        })
+    }
 
-        for i in 0..iter_count {
-            let cmd_buffer = &mut command_buffers[i];
-            let framebuffer = &framebuffers[i];
-            let uniform_buffer = &uniform_buffers[i];
-            cmd_buffer.begin(true);
+    /// Waits until the last frame that rendered to swapchain `image` is
+    /// done and hands the image to `frame`, whose own fence must have been
+    /// waited for already.
+    pub(super) unsafe fn wait_for_image(&mut self, image: usize, frame: usize) -> Result<(), RenderError> {
+        if image >= self.images_in_flight.len() {
+            self.images_in_flight.resize(image + 1, None);
+        }
+        if let Some(previous_frame) = self.images_in_flight[image] {
+            if previous_frame != frame {
+                let fence = &self.fences.as_ref().unwrap()[previous_frame];
+                self.device.borrow().device.wait_for_fence(fence, !0)?;
+            }
+        }
+        self.images_in_flight[image] = Some(frame);
+        Ok(())
+    }
 
-            {
-                let mut encoder = cmd_buffer.begin_render_pass_inline(
-                    render_pass.render_pass.as_ref().unwrap(),
-                    &framebuffer,
-                    pso::Rect {
-                        x: 0,
-                        y: 0,
-                        w: swapchain.extent.width as i16,
-                        h: swapchain.extent.height as i16,
-                    },
-                    &[command::ClearValue::Color(
-                        command::ClearColor::Sfloat([
-                            0.0, 0.0, 0.0, 1.0,
-                        ])
-                    ), command::ClearValue::DepthStencil(
-                        command::ClearDepthStencil(1.0, 0)
-                    )]
-                );
+    /// Forgets which frames used the swapchain images, once the device is
+    /// idle and the swapchain replaced.
+    pub(super) fn reset_images_in_flight(&mut self) {
+        self.images_in_flight.clear();
+    }
 
-                encoder.bind_graphics_pipeline(
-                    pipeline.pipeline.as_ref().unwrap());
-                encoder.bind_vertex_buffers(0, Some((vertex_buffer.get_buffer(), 0)));
+    /// Records the command buffer of `frame` again, drawing `draw_calls`
+    /// into the `viewport` area of `framebuffer`. The frame's fence must
+    /// have been waited for.
+    /// Pipelines are bound as the draw calls switch between them, so
+    /// calls sharing one should be next to each other. They all have to
+    /// use `pipeline_layout`.
+    pub(super) unsafe fn record(
+        &mut self,
+        frame: usize,
+        render_pass: &RenderPassState<B>,
+        framebuffer: &B::Framebuffer,
+        viewport: &pso::Viewport,
+        pipeline_layout: &B::PipelineLayout,
+        uniform_set: &B::DescriptorSet,
+        draw_calls: &[DrawCall<B>],
+        clear_color: [f32; 4]
+    ) {
+        self.command_pools.as_mut().unwrap()[frame].reset();
+
+        let cmd_buffer = &mut self.command_buffers.as_mut().unwrap()[frame];
+        cmd_buffer.begin();
+
+        {
+            let mut encoder = cmd_buffer.begin_render_pass_inline(
+                render_pass.render_pass.as_ref().unwrap(),
+                framebuffer,
+                viewport.rect,
+                &[command::ClearValue::Color(
+                    command::ClearColor::Sfloat(clear_color)
+                ), command::ClearValue::DepthStencil(
+                    command::ClearDepthStencil(1.0, 0)
+                )]
+            );
+
+            encoder.set_viewports(0, &[viewport.clone()]);
+            encoder.set_scissors(0, &[viewport.rect]);
+            encoder.bind_graphics_descriptor_sets(
+                pipeline_layout,
+                0,
+                vec![uniform_set],
+                &[]
+            );
+            let mut bound_pipeline: Option<&PipelineState<B>> = None;
+            for draw_call in draw_calls {
+                if !bound_pipeline.map_or(false, |bound| std::ptr::eq(bound, draw_call.pipeline)) {
+                    encoder.bind_graphics_pipeline(
+                        draw_call.pipeline.pipeline.as_ref().unwrap());
+                    bound_pipeline = Some(draw_call.pipeline);
+                }
+                encoder.bind_vertex_buffers(
+                    0, Some((draw_call.vertex_buffer.get_buffer(), 0)));
                 encoder.bind_index_buffer(IndexBufferView {
-                    buffer: index_buffer.get_buffer(),
+                    buffer: draw_call.index_buffer.get_buffer(),
                     offset: 0,
-                    index_type: index_buffer.index_type()
+                    index_type: draw_call.index_buffer.index_type()
                 });
                 encoder.bind_graphics_descriptor_sets(
-                    pipeline.pipeline_layout.as_ref().unwrap(),
-                    0,
-                    vec![uniform_buffer.get_descriptor_set()],
+                    pipeline_layout,
+                    1,
+                    vec![draw_call.material_set],
                     &[]
                 );
-                encoder.draw_indexed(0..index_count, 0, 0..1);
-
-                // explicit end_render_pass on Drop
+                encoder.push_graphics_constants(
+                    pipeline_layout,
+                    pso::ShaderStageFlags::VERTEX,
+                    0,
+                    &mat4_push_constants(&draw_call.transform)
+                );
+                encoder.draw_indexed(draw_call.indicies.clone(), 0, 0..1);
             }
 
-            cmd_buffer.finish();
+            // explicit end_render_pass on Drop
         }
 
-        CommandBufferState {
-            command_pool: Some(command_pool),
-            command_buffers: Some(command_buffers),
-            acquire_semaphores: Some(acquire_semaphores),
-            present_semaphores: Some(present_semaphores),
-            fences: Some(fences),
-            device
-        }
+        cmd_buffer.finish();
     }
 }
 
+fn mat4_push_constants(matrix: &Mat4) -> Vec<u32> {
+    matrix.as_slice().iter().map(|value| value.to_bits()).collect()
+}
+
 impl<B: Backend> Drop for CommandBufferState<B> {
     fn drop(&mut self) {
         let device = &self.device.borrow().device;
         unsafe {
             for fence in self.fences.take().unwrap() {
-                device.wait_for_fence(&fence, !0).unwrap();
+                // fails only if the device was lost, so nothing is running
+                let _ = device.wait_for_fence(&fence, !0);
                 device.destroy_fence(fence);
             }
 
@@ -143,12 +203,19 @@ impl<B: Backend> Drop for CommandBufferState<B> {
                 device.destroy_semaphore(present_semaphore);
             }
 
+            #[allow(unused_mut)]
+            let mut command_pools = self.command_pools.take().unwrap();
             #[cfg(feature="vulkan")]
-            self.command_pool.as_mut().unwrap()
-                .free(self.command_buffers.take().unwrap());
+            for (command_pool, command_buffer) in command_pools
+                .iter_mut()
+                .zip(self.command_buffers.take().unwrap())
+            {
+                command_pool.free(Some(command_buffer));
+            }
 
-            device.destroy_command_pool(
-                self.command_pool.take().unwrap().into_raw());
+            for command_pool in command_pools {
+                device.destroy_command_pool(command_pool.into_raw());
+            }
         }
     }
 }
@@ -3,6 +3,7 @@ use std::rc::Rc;
 use gfx_hal::{Backend, Device, DescriptorPool};
 use gfx_hal::pso;
 use super::device::DeviceState;
+use super::error::RenderError;
 
 pub(super) struct DescriptorSetLayout<B: Backend> {
     layout: Option<B::DescriptorSetLayout>,
@@ -13,36 +14,35 @@ impl<B: Backend> DescriptorSetLayout<B> {
     pub(super) unsafe fn new(
         device: Rc<RefCell<DeviceState<B>>>,
         bindings: Vec<pso::DescriptorSetLayoutBinding>,
-    ) -> Self {
+    ) -> Result<Self, RenderError> {
         let desc_set_layout = device
             .borrow()
             .device
-            .create_descriptor_set_layout(bindings, &[])
-            .ok();
+            .create_descriptor_set_layout(bindings, &[])?;
 
-        DescriptorSetLayout {
-            layout: desc_set_layout,
+        Ok(DescriptorSetLayout {
+            layout: Some(desc_set_layout),
             device,
-        }
+        })
     }
 
     pub(super) fn get_layout(&self) -> &B::DescriptorSetLayout {
         self.layout.as_ref().unwrap()
     }
 
-    pub(super) unsafe fn create_desc_sets(&self, desc_pool: &mut B::DescriptorPool, size: usize) -> Vec<DescriptorSet<B>> {
+    pub(super) unsafe fn create_desc_sets(
+        &self, desc_pool: &mut B::DescriptorPool, size: usize
+    ) -> Result<Vec<DescriptorSet<B>>, RenderError> {
         let mut results = Vec::with_capacity(size);
         let layout = self.layout.as_ref().unwrap();
         let layouts = std::iter::repeat(layout).take(size);
         //let layouts: Vec<_> = (0..size).map(|_| layout.clone()).collect();
-        desc_pool
-            .allocate_sets(layouts, &mut results)
-            .unwrap();
-        results.into_iter().map(|desc_set| {
+        desc_pool.allocate_sets(layouts, &mut results)?;
+        Ok(results.into_iter().map(|desc_set| {
             DescriptorSet {
                 set: Some(desc_set),
             }
-        }).collect()
+        }).collect())
     }
 
 }
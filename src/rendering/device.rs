@@ -1,63 +1,129 @@
-use gfx_hal::{Adapter, Backend, Graphics, QueueGroup, QueueFamily,
-              Capability, Surface, Gpu, PhysicalDevice, Features};
-use gfx_hal::format;
+use gfx_hal::{Adapter, Backend, Graphics, Transfer, QueueGroup, QueueFamily,
+              QueueType, Capability, Surface, Gpu, PhysicalDevice, Features};
+use gfx_hal::memory::{Properties, Requirements};
+use log::debug;
 
+use super::allocator::{Allocation, MemoryAllocator};
+use super::error::RenderError;
+
+/// Features the renderer can't work without. Adapters missing any of them
+/// are never picked.
+pub(super) fn required_features() -> Features {
+    Features::empty()
+}
+
+/// Features that are enabled when the adapter has them.
+fn optional_features() -> Features {
+    Features::SAMPLER_ANISOTROPY | Features::NON_FILL_POLYGON_MODE
+}
+
+/// The logical device. The adapter it is opened from is kept by
+/// `AdapterState`, so a lost device can be opened again.
 pub(super) struct DeviceState<B: Backend> {
     pub(super) device: B::Device,
-    pub(super) physical_device: B::PhysicalDevice,
     pub(super) queues: QueueGroup<B, Graphics>,
+    /// A queue of another family that can copy, so uploads run alongside
+    /// rendering. `None` if the adapter has no such family.
+    pub(super) transfer_queues: Option<QueueGroup<B, Transfer>>,
+    /// Everything the device was opened with.
+    pub(super) features: Features,
+    /// Every resource's memory comes from here.
+    pub(super) allocator: MemoryAllocator<B>,
 }
 
 impl<B: Backend> DeviceState<B> {
-    pub(super) fn new(adapter: Adapter<B>, surface: &B::Surface) -> Self {
+    pub(super) fn new(
+        adapter: &Adapter<B>, surface: Option<&B::Surface>
+    ) -> Result<Self, RenderError> {
         // code taken from gfx_hal::adapter::Adapter::open_with
         // to manually add in features enabling
         let requested_family = adapter
             .queue_families.iter()
             .find(|family| {
-                Graphics::supported_by(family.queue_type()) && surface.supports_queue_family(family) && 1 <= family.max_queues()
+                Graphics::supported_by(family.queue_type())
+                    && surface.map_or(true, |surface| surface.supports_queue_family(family))
+                    && 1 <= family.max_queues()
             });
+        let family = requested_family.ok_or_else(|| RenderError::Unsupported(
+            "no queue family supports graphics and presentation".to_string()))?;
+
+        // families that can only copy are usually DMA engines, other non
+        // graphics families are compute ones that can copy too
+        let transfer_family = adapter
+            .queue_families.iter()
+            .filter(|transfer_family| {
+                transfer_family.id() != family.id()
+                    && Transfer::supported_by(transfer_family.queue_type())
+                    && !Graphics::supported_by(transfer_family.queue_type())
+                    && 1 <= transfer_family.max_queues()
+            })
+            .min_by_key(|transfer_family| transfer_family.queue_type() != QueueType::Transfer);
+        match transfer_family {
+            Some(transfer_family) => debug!(
+                "Uploading through {:?} queue family {:?}",
+                transfer_family.queue_type(), transfer_family.id()),
+            None => debug!("No separate transfer queue family, uploading through the graphics queue"),
+        }
+
         let priorities = vec![1.0; 1];
-        let (id, families) = match requested_family {
-            Some(family) => (
-                family.id(),
-                [(family, priorities.as_slice())]
-            ),
-            _ => panic!("Device initialization failed")
-        };
+        let families: Vec<_> = std::iter::once(family)
+            .chain(transfer_family)
+            .map(|family| (family, priorities.as_slice()))
+            .collect();
+
+        let features = required_features()
+            | (optional_features() & adapter.physical_device.features());
+        debug!("Enabled features: {:?}", features);
 
         let Gpu { device, mut queues } =
             unsafe {
                 adapter.physical_device.open(
                     &families,
-                    Features::SAMPLER_ANISOTROPY
-                ).unwrap()
+                    features
+                )?
             };
 
-        DeviceState {
+        let memory_properties = adapter.physical_device.memory_properties();
+
+        Ok(DeviceState {
             device,
-            queues: queues.take(id).unwrap(),
-            physical_device: adapter.physical_device,
-        }
+            queues: queues.take(family.id()).unwrap(),
+            transfer_queues: transfer_family
+                .map(|transfer_family| queues.take(transfer_family.id()).unwrap()),
+            features,
+            allocator: MemoryAllocator::new(
+                memory_properties.memory_types,
+                &memory_properties.memory_heaps),
+        })
     }
 
-    pub(super) fn format_properties(
-        &self, format: Option<format::Format>
-    ) -> format::Properties {
-        self.physical_device.format_properties(format)
+    /// `linear` is true for buffers and linearly tiled images.
+    pub(super) unsafe fn allocate(
+        &mut self,
+        requirements: Requirements,
+        properties: Properties,
+        linear: bool
+    ) -> Result<Allocation, RenderError> {
+        self.allocator.allocate(&self.device, requirements, properties, linear)
     }
 
-    pub(super) fn optimal_depth_format(&self) -> Option<format::Format> {
-        let format_candidates = vec![format::Format::D32Sfloat,
-                                     format::Format::D32SfloatS8Uint,
-                                     format::Format::D24UnormS8Uint];
-        let reqs = format::ImageFeature::DEPTH_STENCIL_ATTACHMENT;
-        for format in format_candidates {
-            let props = self.format_properties(Some(format));
-            if props.optimal_tiling & reqs == reqs {
-                return Some(format)
-            }
+    pub(super) unsafe fn free(&mut self, allocation: Allocation) {
+        self.allocator.free(&self.device, allocation);
+    }
+
+    pub(super) fn memory(&self, allocation: &Allocation) -> &B::Memory {
+        self.allocator.memory(allocation)
+    }
+
+    pub(super) fn mapping(&self, allocation: &Allocation) -> Option<*mut u8> {
+        self.allocator.mapping(allocation)
+    }
+}
+
+impl<B: Backend> Drop for DeviceState<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.allocator.dispose(&self.device);
         }
-        None
     }
 }
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::io;
+use gfx_hal::{buffer, device, error, image, pso, window, AcquireError};
+use gfx_hal::memory::Properties;
+
+use super::assets::AssetError;
+
+/// Everything that can go wrong while setting up or running the renderer.
+/// Backend errors that need no special handling are kept as their
+/// description in `Backend`.
+#[derive(Debug)]
+pub enum RenderError {
+    /// Host or device memory ran out.
+    OutOfMemory,
+    DeviceLost,
+    SurfaceLost,
+    /// No memory type has the properties a resource needs.
+    NoMemoryType(Properties),
+    Shader(String),
+    Asset(AssetError),
+    /// The adapter or surface lacks something the renderer needs.
+    Unsupported(String),
+    Io(io::Error),
+    Backend(String),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::OutOfMemory => write!(f, "out of memory"),
+            RenderError::DeviceLost => write!(f, "graphics device lost"),
+            RenderError::SurfaceLost => write!(f, "window surface lost"),
+            RenderError::NoMemoryType(properties) =>
+                write!(f, "no memory type with properties {:?}", properties),
+            RenderError::Shader(e) => write!(f, "shader error: {}", e),
+            RenderError::Asset(e) => write!(f, "{}", e),
+            RenderError::Unsupported(what) => write!(f, "unsupported: {}", what),
+            RenderError::Io(e) => write!(f, "{}", e),
+            RenderError::Backend(e) => write!(f, "backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl From<AssetError> for RenderError {
+    fn from(e: AssetError) -> Self {
+        RenderError::Asset(e)
+    }
+}
+
+impl From<io::Error> for RenderError {
+    fn from(e: io::Error) -> Self {
+        RenderError::Io(e)
+    }
+}
+
+impl From<device::OutOfMemory> for RenderError {
+    fn from(_: device::OutOfMemory) -> Self {
+        RenderError::OutOfMemory
+    }
+}
+
+impl From<device::DeviceLost> for RenderError {
+    fn from(_: device::DeviceLost) -> Self {
+        RenderError::DeviceLost
+    }
+}
+
+impl From<device::OomOrDeviceLost> for RenderError {
+    fn from(e: device::OomOrDeviceLost) -> Self {
+        match e {
+            device::OomOrDeviceLost::OutOfMemory(_) => RenderError::OutOfMemory,
+            device::OomOrDeviceLost::DeviceLost(_) => RenderError::DeviceLost,
+        }
+    }
+}
+
+impl From<device::AllocationError> for RenderError {
+    fn from(e: device::AllocationError) -> Self {
+        match e {
+            device::AllocationError::OutOfMemory(_) => RenderError::OutOfMemory,
+            e => RenderError::Backend(format!("{:?}", e)),
+        }
+    }
+}
+
+impl From<device::BindError> for RenderError {
+    fn from(e: device::BindError) -> Self {
+        match e {
+            device::BindError::OutOfMemory(_) => RenderError::OutOfMemory,
+            e => RenderError::Backend(format!("{:?}", e)),
+        }
+    }
+}
+
+impl From<device::MapError> for RenderError {
+    fn from(e: device::MapError) -> Self {
+        match e {
+            device::MapError::OutOfMemory(_) => RenderError::OutOfMemory,
+            e => RenderError::Backend(format!("{:?}", e)),
+        }
+    }
+}
+
+impl From<device::ShaderError> for RenderError {
+    fn from(e: device::ShaderError) -> Self {
+        RenderError::Shader(format!("{:?}", e))
+    }
+}
+
+impl From<pso::CreationError> for RenderError {
+    fn from(e: pso::CreationError) -> Self {
+        match e {
+            pso::CreationError::OutOfMemory(_) => RenderError::OutOfMemory,
+            pso::CreationError::Shader(e) => e.into(),
+            e => RenderError::Backend(format!("{:?}", e)),
+        }
+    }
+}
+
+impl From<pso::AllocationError> for RenderError {
+    fn from(e: pso::AllocationError) -> Self {
+        RenderError::Backend(format!("descriptor set allocation failed: {:?}", e))
+    }
+}
+
+impl From<buffer::CreationError> for RenderError {
+    fn from(e: buffer::CreationError) -> Self {
+        RenderError::Backend(format!("buffer creation failed: {:?}", e))
+    }
+}
+
+impl From<image::CreationError> for RenderError {
+    fn from(e: image::CreationError) -> Self {
+        RenderError::Backend(format!("image creation failed: {:?}", e))
+    }
+}
+
+impl From<image::ViewError> for RenderError {
+    fn from(e: image::ViewError) -> Self {
+        RenderError::Backend(format!("image view creation failed: {:?}", e))
+    }
+}
+
+impl From<window::CreationError> for RenderError {
+    fn from(e: window::CreationError) -> Self {
+        match e {
+            window::CreationError::OutOfMemory(_) => RenderError::OutOfMemory,
+            window::CreationError::DeviceLost(_) => RenderError::DeviceLost,
+            window::CreationError::SurfaceLost(_) => RenderError::SurfaceLost,
+            e => RenderError::Backend(format!("swapchain creation failed: {:?}", e)),
+        }
+    }
+}
+
+impl From<error::DeviceCreationError> for RenderError {
+    fn from(e: error::DeviceCreationError) -> Self {
+        RenderError::Backend(format!("device creation failed: {:?}", e))
+    }
+}
+
+impl From<error::HostExecutionError> for RenderError {
+    fn from(e: error::HostExecutionError) -> Self {
+        RenderError::Backend(format!("{:?}", e))
+    }
+}
+
+/// `OutOfDate` is not an error to the renderer, it recreates the swapchain
+/// instead, so it is handled before converting.
+impl From<AcquireError> for RenderError {
+    fn from(e: AcquireError) -> Self {
+        match e {
+            AcquireError::OutOfMemory(_) => RenderError::OutOfMemory,
+            AcquireError::DeviceLost(_) => RenderError::DeviceLost,
+            AcquireError::SurfaceLost(_) => RenderError::SurfaceLost,
+            e => RenderError::Backend(format!("can't acquire swapchain image: {:?}", e)),
+        }
+    }
+}
+
+impl From<window::PresentError> for RenderError {
+    fn from(e: window::PresentError) -> Self {
+        match e {
+            window::PresentError::OutOfMemory(_) => RenderError::OutOfMemory,
+            window::PresentError::DeviceLost(_) => RenderError::DeviceLost,
+            window::PresentError::SurfaceLost(_) => RenderError::SurfaceLost,
+            e => RenderError::Backend(format!("can't present: {:?}", e)),
+        }
+    }
+}
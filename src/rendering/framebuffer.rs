@@ -5,8 +5,10 @@ use gfx_hal::format;
 use gfx_hal::image::{Extent, ViewKind};
 
 use super::device::DeviceState;
+use super::error::RenderError;
 use super::render_pass::RenderPassState;
 use super::swapchain::SwapchainState;
+use super::offscreen::OffscreenState;
 use super::images::{DepthImage, ColorImage};
 use super::constants::COLOR_RANGE;
 
@@ -23,7 +25,7 @@ impl<B: Backend> FramebufferState<B> {
         swapchain: &mut SwapchainState<B>,
         color_image: &ColorImage<B>,
         depth_image: &DepthImage<B>
-    ) -> Self {
+    ) -> Result<Self, RenderError> {
         let (frame_images, framebuffers) = {
             let extent = Extent {
                 width: swapchain.extent.width as _,
@@ -42,11 +44,10 @@ impl<B: Backend> FramebufferState<B> {
                             swapchain.format,
                             format::Swizzle::NO,
                             COLOR_RANGE.clone()
-                        )
-                        .unwrap();
-                    (image, rtv)
+                        )?;
+                    Ok((image, rtv))
                 })
-                .collect::<Vec<_>>();
+                .collect::<Result<Vec<_>, RenderError>>()?;
 
             let color_image_view = color_image.image_view.as_ref().unwrap();
             let depth_image_view = depth_image.image_view.as_ref().unwrap();
@@ -71,18 +72,47 @@ impl<B: Backend> FramebufferState<B> {
                             attachments,
                             extent,
                         )
-                        .unwrap()
                 })
-                .collect();
+                .collect::<Result<_, _>>()?;
 
             (pairs, fbos)
         };
 
-        FramebufferState {
+        Ok(FramebufferState {
             frame_images: Some(frame_images),
             framebuffers: Some(framebuffers),
             device
-        }
+        })
+    }
+
+    /// A single framebuffer resolving into the offscreen image. There are
+    /// no swapchain images to own, so `frame_images` is left empty.
+    pub(super) unsafe fn new_offscreen(
+        device: Rc<RefCell<DeviceState<B>>>,
+        render_pass: &RenderPassState<B>,
+        offscreen: &OffscreenState<B>,
+        color_image: &ColorImage<B>,
+        depth_image: &DepthImage<B>
+    ) -> Result<Self, RenderError> {
+        let attachments: Vec<&B::ImageView> = vec![
+            color_image.image_view.as_ref().unwrap(),
+            depth_image.image_view.as_ref().unwrap(),
+            offscreen.image_view.as_ref().unwrap(),
+        ];
+        let framebuffer = device
+            .borrow()
+            .device
+            .create_framebuffer(
+                render_pass.render_pass.as_ref().unwrap(),
+                attachments,
+                offscreen.extent,
+            )?;
+
+        Ok(FramebufferState {
+            frame_images: Some(vec![]),
+            framebuffers: Some(vec![framebuffer]),
+            device
+        })
     }
 }
 
@@ -0,0 +1,277 @@
+use std::fmt;
+use std::path::Path;
+use std::time::Instant;
+use gltf::material::AlphaMode;
+use gltf::mesh::Mode;
+use gltf::image::Format as GltfFormat;
+
+use glm::{Mat4, vec2, vec3, vec4};
+
+use log::debug;
+
+use super::pipeline_desc::{BlendMode, CullFace, PipelineDesc};
+use super::primitives::{Material, Mesh, Model, TextureSource, Vertex};
+
+#[derive(Debug)]
+pub enum GltfError {
+    Import(gltf::Error),
+    UnsupportedImageFormat(usize, GltfFormat),
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GltfError::Import(e) => write!(f, "{}", e),
+            GltfError::UnsupportedImageFormat(index, format) =>
+                write!(f, "image {} has unsupported format {:?}", index, format),
+        }
+    }
+}
+
+impl From<gltf::Error> for GltfError {
+    fn from(e: gltf::Error) -> Self {
+        GltfError::Import(e)
+    }
+}
+
+impl Model {
+    /// Loads a `.gltf` or `.glb` file. Node transforms are baked into the
+    /// vertices and every primitive becomes its own mesh.
+    pub fn load_gltf(file: &Path) -> Result<Self, GltfError> {
+        debug!("Reading glTF file: {:?}", file);
+        let start_time = Instant::now();
+        let (document, buffers, gltf_images) = gltf::import(file)?;
+        debug!("Reading took {}s", start_time.elapsed().as_millis() as f64 / 1000.0);
+
+        let images = gltf_images
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| convert_image(index, data))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let materials = document
+            .materials()
+            .map(|material| {
+                let pbr = material.pbr_metallic_roughness();
+                let color = pbr.base_color_factor();
+                Material {
+                    name: material.name().unwrap_or("").to_string(),
+                    diffuse: vec3(color[0], color[1], color[2]),
+                    diffuse_texture: pbr.base_color_texture().map(|info| {
+                        TextureSource::Embedded(info.texture().source().index())
+                    }),
+                    pipeline: material_pipeline(&material),
+                }
+            })
+            .collect();
+
+        let mut model = Model {
+            vertices: Vec::new(),
+            indicies: Vec::new(),
+            meshes: Vec::new(),
+            materials,
+            images
+        };
+
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next());
+        if let Some(scene) = scene {
+            for node in scene.nodes() {
+                model.add_gltf_node(&node, &Mat4::identity(), &buffers);
+            }
+        }
+
+        debug!("Done loading model: {:?}. Took: {}s", file, start_time.elapsed().as_millis() as f64 / 1000.0);
+        Ok(model)
+    }
+
+    fn add_gltf_node(
+        &mut self,
+        node: &gltf::Node,
+        parent_transform: &Mat4,
+        buffers: &[gltf::buffer::Data]
+    ) {
+        let local: Vec<f32> = node.transform()
+            .matrix()
+            .iter()
+            .flat_map(|column| column.iter().cloned())
+            .collect();
+        let transform = parent_transform * glm::make_mat4(&local);
+        let normal_transform = glm::mat4_to_mat3(
+            &glm::transpose(&glm::inverse(&transform)));
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                if primitive.mode() != Mode::Triangles {
+                    debug!("Skipping {:?} primitive in mesh {:?}", primitive.mode(), mesh.name());
+                    continue;
+                }
+
+                let reader = primitive.reader(|buffer| Some(&*buffers[buffer.index()]));
+                let positions: Vec<[f32; 3]> = match reader.read_positions() {
+                    Some(positions) => positions.collect(),
+                    None => continue,
+                };
+                let normals: Vec<[f32; 3]> = reader.read_normals()
+                    .map(|normals| normals.collect())
+                    .unwrap_or_default();
+                let tex_coords: Vec<[f32; 2]> = reader.read_tex_coords(0)
+                    .map(|tex_coords| tex_coords.into_f32().collect())
+                    .unwrap_or_default();
+
+                let material = primitive.material().index();
+                let color = material
+                    .map(|id| self.materials[id].diffuse)
+                    .unwrap_or(vec3(1.0, 1.0, 1.0));
+
+                let base_vertex = self.vertices.len() as u32;
+                for (i, p) in positions.iter().enumerate() {
+                    let pos = transform * vec4(p[0], p[1], p[2], 1.0);
+                    let normal = match normals.get(i) {
+                        Some(n) => glm::normalize(&(normal_transform * vec3(n[0], n[1], n[2]))),
+                        None => vec3(0.0, 0.0, 0.0),
+                    };
+                    let tex_coord = tex_coords.get(i).cloned().unwrap_or([0.0, 0.0]);
+                    self.vertices.push(Vertex {
+                        pos: vec3(pos.x, pos.y, pos.z),
+                        color,
+                        tex_coord: vec2(tex_coord[0], tex_coord[1]),
+                        normal
+                    });
+                }
+
+                let first_index = self.indicies.len() as u32;
+                match reader.read_indices() {
+                    Some(indices) => self.indicies.extend(
+                        indices.into_u32().map(|index| base_vertex + index)),
+                    None => self.indicies.extend(
+                        base_vertex..base_vertex + positions.len() as u32),
+                }
+                self.meshes.push(Mesh {
+                    indicies: first_index..self.indicies.len() as u32,
+                    material
+                });
+            }
+        }
+
+        for child in node.children() {
+            self.add_gltf_node(&child, &transform, buffers);
+        }
+    }
+}
+
+/// Cutouts use alpha to coverage rather than sorting. Blended materials
+/// don't write depth, so what is behind them still shows through.
+fn material_pipeline(material: &gltf::Material) -> PipelineDesc {
+    let mut desc = PipelineDesc::default();
+    match material.alpha_mode() {
+        AlphaMode::Opaque => {},
+        AlphaMode::Mask => desc.multisampling.alpha_coverage = true,
+        AlphaMode::Blend => {
+            desc.blend = BlendMode::Alpha;
+            desc.depth.write = false;
+        },
+    }
+    if material.double_sided() {
+        desc.raster.cull_face = CullFace::None;
+    }
+    desc
+}
+
+fn convert_image(index: usize, data: gltf::image::Data) -> Result<image::RgbaImage, GltfError> {
+    let (width, height) = (data.width, data.height);
+    let image = match data.format {
+        GltfFormat::R8G8B8A8 =>
+            image::RgbaImage::from_raw(width, height, data.pixels)
+                .map(image::DynamicImage::ImageRgba8),
+        GltfFormat::R8G8B8 =>
+            image::RgbImage::from_raw(width, height, data.pixels)
+                .map(image::DynamicImage::ImageRgb8),
+        GltfFormat::R8G8 =>
+            image::GrayAlphaImage::from_raw(width, height, data.pixels)
+                .map(image::DynamicImage::ImageLumaA8),
+        GltfFormat::R8 =>
+            image::GrayImage::from_raw(width, height, data.pixels)
+                .map(image::DynamicImage::ImageLuma8),
+        #[allow(unreachable_patterns)]
+        format => return Err(GltfError::UnsupportedImageFormat(index, format)),
+    };
+    image
+        .map(|image| image.to_rgba())
+        .ok_or(GltfError::UnsupportedImageFormat(index, data.format))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use glm::vec3;
+
+    use super::super::pipeline_desc::{BlendMode, CullFace, PipelineDesc};
+    use super::super::primitives::Model;
+
+    /// A parent node translated by one along x holding a mesh with two
+    /// indexed primitives, and a child scaled by two holding a mesh with
+    /// one unindexed primitive. All three are the same unit triangle.
+    fn load_fixture() -> Model {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/nodes.gltf");
+        Model::load_gltf(&path).unwrap()
+    }
+
+    #[test]
+    fn node_transforms_accumulate() {
+        let model = load_fixture();
+        let positions: Vec<_> = model.vertices.iter().map(|vertex| vertex.pos).collect();
+
+        assert_eq!(positions.len(), 9);
+        for primitive in 0..2 {
+            assert_eq!(positions[primitive * 3..primitive * 3 + 3], [
+                vec3(1.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0)
+            ]);
+        }
+        assert_eq!(positions[6..9], [
+            vec3(1.0, 0.0, 0.0), vec3(3.0, 0.0, 0.0), vec3(1.0, 2.0, 0.0)
+        ]);
+    }
+
+    #[test]
+    fn indices_are_offset_per_primitive() {
+        let model = load_fixture();
+
+        assert_eq!(model.indicies, (0..9).collect::<Vec<u32>>());
+        let ranges: Vec<_> = model.meshes.iter().map(|mesh| mesh.indicies.clone()).collect();
+        assert_eq!(ranges, vec![0..3, 3..6, 6..9]);
+        let materials: Vec<_> = model.meshes.iter().map(|mesh| mesh.material).collect();
+        assert_eq!(materials, vec![Some(0), Some(0), None]);
+    }
+
+    #[test]
+    fn material_color_is_baked_into_vertices() {
+        let model = load_fixture();
+
+        assert_eq!(model.vertices[0].color, vec3(0.5, 0.25, 1.0));
+        assert_eq!(model.vertices[8].color, vec3(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn blended_double_sided_material_pipeline() {
+        let model = load_fixture();
+        let desc = &model.materials[0].pipeline;
+
+        assert_eq!(desc.blend, BlendMode::Alpha);
+        assert!(!desc.depth.write);
+        assert_eq!(desc.raster.cull_face, CullFace::None);
+        assert_eq!(desc.shaders, PipelineDesc::default().shaders);
+    }
+
+    #[test]
+    fn embedded_rgb_image_is_converted_to_rgba() {
+        let model = load_fixture();
+
+        assert_eq!(model.images.len(), 1);
+        let image = &model.images[0];
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.get_pixel(0, 0).data, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(1, 0).data, [0, 0, 255, 255]);
+    }
+}
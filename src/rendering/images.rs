@@ -1,14 +1,13 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use gfx_hal::{Backend, Device, CommandPool, Graphics};
-use gfx_hal::buffer::Usage as BufferUsage;
+use gfx_hal::{Backend, Device, Features};
 use gfx_hal::image::{Access, Layout, Usage as ImageUsage,
                      Kind, Size, SubresourceLayers, Tiling,
-                     ViewCapabilities, Offset, Extent, ViewKind,
+                     ViewCapabilities, Offset, ViewKind,
                      SamplerInfo, Filter, WrapMode, Anisotropic,
                      Lod, SubresourceRange};
-use gfx_hal::format::{AsFormat, Format, Aspects, Rgba8Unorm, Swizzle, ImageFeature
+use gfx_hal::format::{AsFormat, Format, Aspects, Rgba8Srgb, Rgba8Unorm, Swizzle, ImageFeature
 };
 use gfx_hal::memory::{Barrier, Properties as MemoryProperties, Dependencies as MemoryDependencies};
 use gfx_hal::command;
@@ -16,21 +15,22 @@ use gfx_hal::pso;
 use gfx_hal::pso::PipelineStage;
 
 use image;
+use log::debug;
 
 use super::adapter::AdapterState;
+use super::allocator::Allocation;
 use super::device::DeviceState;
-use super::swapchain::SwapchainState;
+use super::error::RenderError;
 use super::descriptors::DescriptorSet;
-use super::buffer::TextureBuffer;
+use super::upload::UploadManager;
 
-pub(super) const CHALET_JPG_DATA: &'static [u8] = include_bytes!("../../textures/chalet.jpg");
-
-unsafe fn create_image<B: Backend>(
-    device: &B::Device, adapter: &AdapterState<B>, kind: Kind,
+pub(super) unsafe fn create_image<B: Backend>(
+    device: &mut DeviceState<B>, kind: Kind,
     format: Format, tiling: Tiling, usage: ImageUsage,
     properties: MemoryProperties, mip_levels: u8
-) -> (B::Image, B::Memory) {
+) -> Result<(B::Image, Allocation), RenderError> {
     let mut image = device
+        .device
         .create_image(
             kind, // kind
             mip_levels,  // mip_levels
@@ -38,74 +38,81 @@ unsafe fn create_image<B: Backend>(
             tiling, // tiling
             usage, // usage
             ViewCapabilities::empty() // view_capabilities
-        )
-        .unwrap();
-
-    let mem_req = device.get_image_requirements(&image);
-
-    let device_type = adapter
-        .memory_types
-        .iter()
-        .enumerate()
-        .position(|(id, memory_type)| {
-            mem_req.type_mask & (1 << id) != 0
-                && memory_type.properties.contains(
-                    properties)
-        })
-        .unwrap()
-        .into();
-
-    let memory = device.allocate_memory(device_type, mem_req.size).unwrap();
-    device.bind_image_memory(&memory, 0, &mut image).unwrap();
-
-    (image, memory)
+        )?;
+
+    let mem_req = device.device.get_image_requirements(&image);
+
+    let memory = device
+        .allocate(mem_req, properties, tiling == Tiling::Linear)
+        .and_then(|memory| {
+            let bound = device.device.bind_image_memory(
+                device.memory(&memory), memory.offset, &mut image);
+            match bound {
+                Ok(()) => Ok(memory),
+                Err(e) => {
+                    device.free(memory);
+                    Err(e.into())
+                }
+            }
+        });
+    match memory {
+        Ok(memory) => Ok((image, memory)),
+        Err(e) => {
+            device.device.destroy_image(image);
+            Err(e)
+        }
+    }
 }
 
 
 pub(super) struct Texture<B: Backend> {
     device: Rc<RefCell<DeviceState<B>>>,
-    memory: Option<B::Memory>,
+    memory: Option<Allocation>,
     image: Option<B::Image>,
     image_view: Option<B::ImageView>,
     sampler: Option<B::Sampler>,
 }
 
 impl<B: Backend> Texture<B> {
+    /// The pixels are copied and the mip levels generated when `uploads`
+    /// is flushed. With `srgb` they are decoded to linear when sampled,
+    /// otherwise sampled as stored.
     pub(super) unsafe fn new(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
         adapter: &AdapterState<B>,
-        command_pool: &mut CommandPool<B, Graphics>,
-        img: &image::ImageBuffer<image::Rgba<u8>, Vec<u8>>
-    ) -> Self {
-
-        let props = device_ptr.borrow().format_properties(Some(Rgba8Unorm::SELF));
-        if props.optimal_tiling & ImageFeature::SAMPLED_LINEAR != ImageFeature::SAMPLED_LINEAR {
-            panic!("texture image format does not support linear blitting!");
-        }
-
-        let (buffer, width, height, row_pitch, stride) = {
-            TextureBuffer::new(
-                Rc::clone(&device_ptr),
-                &adapter,
-                &img,
-                BufferUsage::TRANSFER_SRC
-            )
-        };
-
+        uploads: &mut UploadManager<B>,
+        img: &image::ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+        srgb: bool
+    ) -> Result<Self, RenderError> {
+        let format = if srgb { Rgba8Srgb::SELF } else { Rgba8Unorm::SELF };
+
+        let props = adapter.format_properties(Some(format));
+        let linear_filter = props.optimal_tiling.contains(ImageFeature::SAMPLED_LINEAR);
+        let blit_mipmaps = props.optimal_tiling.contains(
+            ImageFeature::SAMPLED_LINEAR | ImageFeature::BLIT_SRC | ImageFeature::BLIT_DST);
+
+        let (width, height) = img.dimensions();
         let mip_levels = (std::cmp::max(width, height) as f64)
             .log2()
             .floor() as u8 + 1;
 
+        // without blitting every level is scaled down here and uploaded,
+        // otherwise only the first one is and the GPU does the rest
+        let cpu_levels = if blit_mipmaps {
+            vec![]
+        } else {
+            debug!("Generating {} mip levels on the CPU", mip_levels);
+            downscaled_levels(img, mip_levels)
+        };
         let (image, memory) = create_image(
-            &device_ptr.borrow().device,
-            &adapter,
+            &mut device_ptr.borrow_mut(),
             Kind::D2(width as Size, height as Size, 1, 1),
-            Rgba8Unorm::SELF,
+            format,
             Tiling::Optimal,
             ImageUsage::TRANSFER_SRC | ImageUsage::TRANSFER_DST | ImageUsage::SAMPLED,
             MemoryProperties::DEVICE_LOCAL,
             mip_levels
-        );
+        )?;
 
         let subresourcerange = SubresourceRange {
             aspects: Aspects::COLOR,
@@ -115,50 +122,19 @@ impl<B: Backend> Texture<B> {
 
         // copy buffer to texture
         {
-            let mut cmd_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
-            cmd_buffer.begin();
-
-            let image_barrier = Barrier::Image {
-                states: (Access::empty(), Layout::Undefined)
-                    ..(Access::TRANSFER_WRITE, Layout::TransferDstOptimal),
-                target: &image,
-                families: None,
-                range: subresourcerange.clone(),
-            };
-
-            cmd_buffer.pipeline_barrier(
-                PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
-                MemoryDependencies::empty(),
-                &[image_barrier]
-            );
-
-            cmd_buffer.copy_buffer_to_image(
-                (&buffer).get_buffer(),
+            uploads.upload_image(
+                std::iter::once(img).chain(cpu_levels.iter()),
                 &image,
-                Layout::TransferDstOptimal,
-                &[command::BufferImageCopy { // region
-                    buffer_offset: 0,
-                    buffer_width: row_pitch / (stride as u32), // buffer_row_length
-                    buffer_height: height, // buffer_image_height
-                    image_layers: SubresourceLayers { // image_subresource
-                        aspects: Aspects::COLOR,
-                        level: 0,
-                        layers: 0..1
-                    },
-                    image_offset: Offset { x: 0, y: 0, z: 0 },
-                    image_extent: Extent {
-                        width,
-                        height,
-                        depth: 1
-                    }
+                subresourcerange.clone()
+            )?;
 
-                }]
-            );
+            let cmd_buffer = uploads.commands();
 
             let mut src_mip_width = width;
             let mut src_mip_height = height;
 
-            for i in 1..mip_levels {
+            let blit_levels = if blit_mipmaps { 1..mip_levels } else { 0..0 };
+            for i in blit_levels {
                 let image_barrier = Barrier::Image {
                     states: (Access::TRANSFER_WRITE, Layout::TransferDstOptimal)
                         ..(Access::TRANSFER_READ, Layout::TransferSrcOptimal),
@@ -239,6 +215,8 @@ impl<B: Backend> Texture<B> {
                 src_mip_height = dst_mip_height;
             }
 
+            // the levels that were only written to, not blitted from
+            let first_written_level = if blit_mipmaps { mip_levels - 1 } else { 0 };
             let image_barrier = Barrier::Image {
                 states: (Access::TRANSFER_WRITE, Layout::TransferDstOptimal)
                     ..(Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
@@ -246,7 +224,7 @@ impl<B: Backend> Texture<B> {
                 families: None,
                 range: SubresourceRange {
                     aspects: Aspects::COLOR,
-                    levels: (mip_levels - 1)..mip_levels,
+                    levels: first_written_level..mip_levels,
                     layers: 0..1,
                 }
             };
@@ -256,12 +234,6 @@ impl<B: Backend> Texture<B> {
                 MemoryDependencies::empty(),
                 &[image_barrier],
             );
-
-            cmd_buffer.finish();
-
-            let queue = &mut device_ptr.borrow_mut().queues.queues[0];
-            queue.submit_without_semaphores(std::iter::once(&cmd_buffer), None);
-            queue.wait_idle().unwrap();
         }
 
         let (image_view, sampler) = {
@@ -271,32 +243,36 @@ impl<B: Backend> Texture<B> {
                 .create_image_view(
                     &image,
                     ViewKind::D2,
-                    Rgba8Unorm::SELF,
+                    format,
                     Swizzle::NO,
                     subresourcerange
-                )
-                .unwrap();
+                )?;
 
-            let mut sampler_info = SamplerInfo::new(Filter::Linear, WrapMode::Tile);
-            sampler_info.anisotropic = Anisotropic::On(16);
+            let filter = if linear_filter { Filter::Linear } else { Filter::Nearest };
+            let mut sampler_info = SamplerInfo::new(filter, WrapMode::Tile);
+            sampler_info.anisotropic =
+                if device_ptr.borrow().features.contains(Features::SAMPLER_ANISOTROPY) {
+                    Anisotropic::On(16)
+                } else {
+                    Anisotropic::Off
+                };
             let lod0: Lod = 0.0f32.into();
             let lodn: Lod = (mip_levels as f32).into();
             sampler_info.lod_range = lod0..lodn;
             let sampler = device
-                .create_sampler(sampler_info) // TILE = REPEAT
-                .expect("Can't create sampler");
+                .create_sampler(sampler_info)?; // TILE = REPEAT
 
             (image_view, sampler)
         };
 
-        Texture {
+        Ok(Texture {
             device: device_ptr,
             //buffer: Some(buffer),
             memory: Some(memory),
             image: Some(image),
             image_view: Some(image_view),
             sampler: Some(sampler)
-        }
+        })
     }
 
     pub fn write_descriptor_set(
@@ -324,14 +300,33 @@ impl<B: Backend> Texture<B> {
     }
 }
 
+/// Mip levels 1 and up, each half the size of the one before, for devices
+/// that can't blit.
+fn downscaled_levels(img: &image::RgbaImage, mip_levels: u8) -> Vec<image::RgbaImage> {
+    let mut levels: Vec<image::RgbaImage> = Vec::with_capacity(mip_levels as usize);
+    for _ in 1..mip_levels {
+        let next = {
+            let previous = levels.last().unwrap_or(img);
+            let (width, height) = previous.dimensions();
+            image::imageops::resize(
+                previous,
+                std::cmp::max(width / 2, 1),
+                std::cmp::max(height / 2, 1),
+                image::FilterType::Triangle)
+        };
+        levels.push(next);
+    }
+    levels
+}
+
 impl<B: Backend> Drop for Texture<B> {
     fn drop(&mut self) {
-        let device = &self.device.borrow().device;
+        let mut device = self.device.borrow_mut();
         unsafe {
-            device.destroy_sampler(self.sampler.take().unwrap());
-            device.destroy_image_view(self.image_view.take().unwrap());
-            device.destroy_image(self.image.take().unwrap());
-            device.free_memory(self.memory.take().unwrap());
+            device.device.destroy_sampler(self.sampler.take().unwrap());
+            device.device.destroy_image_view(self.image_view.take().unwrap());
+            device.device.destroy_image(self.image.take().unwrap());
+            device.free(self.memory.take().unwrap());
         }
     }
 }
@@ -339,7 +334,7 @@ impl<B: Backend> Drop for Texture<B> {
 
 pub(super) struct DepthImage<B: Backend> {
     device: Rc<RefCell<DeviceState<B>>>,
-    memory: Option<B::Memory>,
+    memory: Option<Allocation>,
     image: Option<B::Image>,
     pub(super) image_view: Option<B::ImageView>
 }
@@ -350,23 +345,24 @@ impl<B: Backend> DepthImage<B> {
         adapter: &AdapterState<B>,
         width: u32,
         height: u32,
-        command_pool: &mut CommandPool<B, Graphics>,
-    ) -> Self {
+        uploads: &mut UploadManager<B>,
+    ) -> Result<Self, RenderError> {
 
         // find optimal depth format
-        let format = device_ptr.borrow().optimal_depth_format().unwrap();
+        let format = adapter
+            .optimal_depth_format()
+            .ok_or_else(|| RenderError::Unsupported("no usable depth format".to_string()))?;
         let samples = adapter.get_max_usable_sample_count();
 
         let (image, memory) = create_image(
-            &device_ptr.borrow().device,
-            &adapter,
+            &mut device_ptr.borrow_mut(),
             Kind::D2(width as Size, height as Size, 1, samples),
             format,
             Tiling::Optimal,
             ImageUsage::DEPTH_STENCIL_ATTACHMENT,
             MemoryProperties::DEVICE_LOCAL,
             1
-        );
+        )?;
 
         let image_view = {
             let device = &device_ptr.borrow().device;
@@ -381,14 +377,12 @@ impl<B: Backend> DepthImage<B> {
                         levels: 0..1,
                         layers: 0..1,
                     }
-                )
-                .unwrap();
+                )?;
             image_view
         };
 
         {
-            let mut cmd_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
-            cmd_buffer.begin();
+            let cmd_buffer = uploads.commands();
 
             let aspects = {
                 if format.is_stencil() {
@@ -414,26 +408,24 @@ impl<B: Backend> DepthImage<B> {
                 MemoryDependencies::empty(),
                 &[image_barrier]
             );
-
-            cmd_buffer.finish();
         }
 
-        DepthImage {
+        Ok(DepthImage {
             device: device_ptr,
             memory: Some(memory),
             image: Some(image),
             image_view: Some(image_view)
-        }
+        })
     }
 }
 
 impl<B: Backend> Drop for DepthImage<B> {
     fn drop(&mut self) {
-        let device = &self.device.borrow().device;
+        let mut device = self.device.borrow_mut();
         unsafe {
-            device.destroy_image_view(self.image_view.take().unwrap());
-            device.destroy_image(self.image.take().unwrap());
-            device.free_memory(self.memory.take().unwrap());
+            device.device.destroy_image_view(self.image_view.take().unwrap());
+            device.device.destroy_image(self.image.take().unwrap());
+            device.free(self.memory.take().unwrap());
         }
     }
 }
@@ -441,7 +433,7 @@ impl<B: Backend> Drop for DepthImage<B> {
 
 pub(super) struct ColorImage<B: Backend> {
     device: Rc<RefCell<DeviceState<B>>>,
-    memory: Option<B::Memory>,
+    memory: Option<Allocation>,
     image: Option<B::Image>,
     pub(super) image_view: Option<B::ImageView>
 }
@@ -450,24 +442,23 @@ impl<B: Backend> ColorImage<B> {
     pub(super) unsafe fn new(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
         adapter: &AdapterState<B>,
-        swapchain: &SwapchainState<B>,
-        command_pool: &mut CommandPool<B, Graphics>,
-    ) -> Self {
+        width: u32,
+        height: u32,
+        format: Format,
+        uploads: &mut UploadManager<B>,
+    ) -> Result<Self, RenderError> {
 
-        let width = swapchain.extent.width;
-        let height = swapchain.extent.height;
         let samples = adapter.get_max_usable_sample_count();
 
         let (image, memory) = create_image(
-            &device_ptr.borrow().device,
-            &adapter,
+            &mut device_ptr.borrow_mut(),
             Kind::D2(width as Size, height as Size, 1, samples),
-            swapchain.format,
+            format,
             Tiling::Optimal,
             ImageUsage::TRANSIENT_ATTACHMENT | ImageUsage::COLOR_ATTACHMENT,
             MemoryProperties::DEVICE_LOCAL,
             1,
-        );
+        )?;
 
         let image_view = {
             let device = &device_ptr.borrow().device;
@@ -475,21 +466,19 @@ impl<B: Backend> ColorImage<B> {
                 .create_image_view(
                     &image,
                     ViewKind::D2,
-                    swapchain.format,
+                    format,
                     Swizzle::NO,
                     SubresourceRange {
                         aspects: Aspects::COLOR,
                         levels: 0..1,
                         layers: 0..1,
                     }
-                )
-                .unwrap();
+                )?;
             image_view
         };
 
         {
-            let mut cmd_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
-            cmd_buffer.begin();
+            let cmd_buffer = uploads.commands();
 
             let image_barrier = Barrier::Image {
                 states: (Access::empty(), Layout::Undefined)
@@ -508,16 +497,14 @@ impl<B: Backend> ColorImage<B> {
                 MemoryDependencies::empty(),
                 &[image_barrier]
             );
-
-            cmd_buffer.finish();
         }
 
-        ColorImage {
+        Ok(ColorImage {
             device: device_ptr,
             memory: Some(memory),
             image: Some(image),
             image_view: Some(image_view)
-        }
+        })
 
     }
 }
@@ -525,11 +512,11 @@ impl<B: Backend> ColorImage<B> {
 
 impl<B: Backend> Drop for ColorImage<B> {
     fn drop(&mut self) {
-        let device = &self.device.borrow().device;
+        let mut device = self.device.borrow_mut();
         unsafe {
-            device.destroy_image_view(self.image_view.take().unwrap());
-            device.destroy_image(self.image.take().unwrap());
-            device.free_memory(self.memory.take().unwrap());
+            device.device.destroy_image_view(self.image_view.take().unwrap());
+            device.device.destroy_image(self.image.take().unwrap());
+            device.free(self.memory.take().unwrap());
         }
     }
 }
@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use gfx_hal::{Backend, Device};
+use gfx_hal::pso;
+
+use super::adapter::AdapterState;
+use super::descriptors::{DescriptorSet, DescriptorSetLayout};
+use super::device::DeviceState;
+use super::error::RenderError;
+use super::images::Texture;
+use super::upload::UploadManager;
+
+/// All textures a model is drawn with, each bound through its own
+/// descriptor set at set index 1 of the pipeline layout.
+pub(super) struct MaterialsState<B: Backend> {
+    desc_pool: Option<B::DescriptorPool>,
+    desc_sets: Vec<DescriptorSet<B>>,
+    textures: Vec<Texture<B>>,
+    layout: DescriptorSetLayout<B>,
+    device: Rc<RefCell<DeviceState<B>>>,
+}
+
+impl<B: Backend> MaterialsState<B> {
+    pub(super) unsafe fn new(
+        device: Rc<RefCell<DeviceState<B>>>,
+        adapter: &AdapterState<B>,
+        uploads: &mut UploadManager<B>,
+        images: &[image::RgbaImage],
+        srgb: bool
+    ) -> Result<Self, RenderError> {
+        let layout = DescriptorSetLayout::new(
+            Rc::clone(&device),
+            vec![
+                pso::DescriptorSetLayoutBinding {
+                    binding: 0,
+                    ty: pso::DescriptorType::CombinedImageSampler,
+                    count: 1,
+                    stage_flags: pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false
+                }
+            ])?;
+
+        let textures: Vec<Texture<B>> = images
+            .iter()
+            .map(|img| Texture::new(
+                Rc::clone(&device),
+                adapter,
+                uploads,
+                img,
+                srgb
+            ))
+            .collect::<Result<_, _>>()?;
+
+        let mut desc_pool = device
+            .borrow()
+            .device
+            .create_descriptor_pool(
+                textures.len(),
+                &[pso::DescriptorRangeDesc {
+                    ty: pso::DescriptorType::CombinedImageSampler,
+                    count: textures.len()
+                }],
+                pso::DescriptorPoolCreateFlags::empty(),
+            )?;
+
+        let desc_sets = match layout.create_desc_sets(&mut desc_pool, textures.len()) {
+            Ok(desc_sets) => desc_sets,
+            Err(e) => {
+                device.borrow().device.destroy_descriptor_pool(desc_pool);
+                return Err(e);
+            }
+        };
+        for (texture, desc) in textures.iter().zip(desc_sets.iter()) {
+            texture.write_descriptor_set(
+                &mut device.borrow_mut().device,
+                desc,
+                0
+            );
+        }
+
+        Ok(MaterialsState {
+            desc_pool: Some(desc_pool),
+            desc_sets,
+            textures,
+            layout,
+            device
+        })
+    }
+
+    pub(super) fn get_layout(&self) -> &B::DescriptorSetLayout {
+        self.layout.get_layout()
+    }
+
+    pub(super) fn get_descriptor_set(&self, texture: usize) -> &B::DescriptorSet {
+        self.desc_sets[texture].set.as_ref().unwrap()
+    }
+}
+
+impl<B: Backend> Drop for MaterialsState<B> {
+    fn drop(&mut self) {
+        self.desc_sets.clear();
+        self.textures.clear();
+        unsafe {
+            self.device
+                .borrow()
+                .device
+                .destroy_descriptor_pool(self.desc_pool.take().unwrap());
+        }
+    }
+}
@@ -2,6 +2,13 @@
 extern crate gfx_backend_dx11 as back;
 #[cfg(feature = "dx12")]
 extern crate gfx_backend_dx12 as back;
+#[cfg(feature = "gl")]
+extern crate gfx_backend_gl as back;
+#[cfg(feature = "metal")]
+extern crate gfx_backend_metal as back;
+#[cfg(feature = "vulkan")]
+extern crate gfx_backend_vulkan as back;
+
 #[cfg(not(any(
     feature = "vulkan",
     feature = "dx11",
@@ -9,21 +16,15 @@ extern crate gfx_backend_dx12 as back;
     feature = "metal",
     feature = "gl"
 )))]
-extern crate gfx_backend_empty as back;
-#[cfg(feature = "gl")]
-extern crate gfx_backend_gl as back;
-#[cfg(feature = "metal")]
-extern crate gfx_backend_metal as back;
-#[cfg(feature = "vulkan")]
-extern crate gfx_backend_vulkan as back;
+compile_error!("Enable one of the backend features: vulkan, metal, dx11, dx12 or gl");
 
 extern crate image;
 
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::io::Cursor;
+use std::path::Path;
 
-use log::debug;
+use log::{debug, info, warn};
 
 use gfx_hal::{
     pso,
@@ -33,64 +34,207 @@ use gfx_hal::{
     Instance,
     Swapchain,
 };
-use gfx_hal::pool::CommandPoolCreateFlags;
+use gfx_hal::image::{Extent, Layout};
+use gfx_hal::format::Format;
+use gfx_hal::window::Extent2D;
 #[cfg(feature="gl")]
 use gfx_hal::format::{AsFormat, Rgba8Srgb as ColorFormat};
 
-use crate::window::WindowState;
+use winit::dpi::LogicalSize;
+#[cfg(feature = "gl")]
+use winit::dpi::PhysicalSize;
+
+use crate::camera::Camera;
+use crate::config::RenderingConfig;
+use crate::window::{WindowState, DEFAULT_WIDTH, DEFAULT_HEIGHT};
 use crate::consts::{APP_TITLE, APP_VERSION};
 
 mod constants;
+mod error;
 mod utils;
 mod primitives;
 mod adapter;
+mod allocator;
 mod device;
 mod swapchain;
 mod render_pass;
 mod pipeline;
+mod pipeline_cache;
+mod pipeline_desc;
 mod framebuffer;
 mod commandbuffer;
 mod buffer;
 mod descriptors;
 mod images;
+mod offscreen;
+mod assets;
+mod materials;
+mod gltf_model;
+mod scene;
+mod upload;
 
 use adapter::AdapterState;
 use device::DeviceState;
 use swapchain::SwapchainState;
 use render_pass::RenderPassState;
-use pipeline::PipelineState;
+use pipeline::{PipelineRegistry, PipelineState};
+use pipeline_cache::PipelineCacheState;
 use framebuffer::FramebufferState;
-use commandbuffer::CommandBufferState;
-use buffer::{VertexBuffer, IndexBuffer, UniformBuffer};
+use commandbuffer::{CommandBufferState, DrawCall};
+use buffer::{ModelBuffers, UniformBuffer};
 use descriptors::DescriptorSetLayout;
-use images::{DepthImage, Texture, ColorImage};
+use images::{DepthImage, ColorImage};
+use offscreen::OffscreenState;
+use materials::MaterialsState;
+use upload::UploadManager;
+
+pub use allocator::MemoryStats;
+pub use error::RenderError;
+pub use primitives::UniformBufferObject;
+pub use assets::{AssetPaths, AssetError, Assets};
+pub use scene::{Scene, SceneObject};
+pub use pipeline_desc::{
+    PipelineDesc, ShaderSet, Topology, VertexLayout, RasterDesc, PolygonMode, CullFace,
+    FrontFace, DepthDesc, Comparison, BlendMode, MultisampleDesc
+};
 
 pub struct BackendState<B: Backend> {
-    surface: B::Surface,
+    surface: Option<B::Surface>,
     adapter: AdapterState<B>,
     #[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
-    #[allow(dead_code)]
-    window: winit::Window,
+    window: Option<winit::Window>,
+    /// Creates a surface for `window` again when it is lost. Holds on to
+    /// the instance, which has to outlive the surface.
+    #[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+    create_surface: Option<Box<dyn Fn(&winit::Window) -> B::Surface>>,
+    /// Kept up to date from window events by `mainloop`. Without a
+    /// surface it is the size of the offscreen image.
+    window_size: Option<LogicalSize>,
+    hidpi_factor: f64,
+    /// The GL context has to be resized by hand before the swapchain is
+    /// recreated, but its window is only reachable through the concrete
+    /// surface type.
+    #[cfg(feature = "gl")]
+    resize_context: fn(&B::Surface, PhysicalSize),
+}
+
+impl<B: Backend> BackendState<B> {
+    /// A backend without a surface renders into an offscreen image.
+    pub fn is_headless(&self) -> bool {
+        self.surface.is_none()
+    }
+
+    /// Size of the window's drawable area in physical pixels.
+    fn window_extent(&self) -> Option<Extent2D> {
+        self.window_size.map(|size| {
+            let size = size.to_physical(self.hidpi_factor);
+            Extent2D {
+                width: size.width.round() as u32,
+                height: size.height.round() as u32
+            }
+        })
+    }
+
+    /// A minimized window has no area to render into.
+    fn is_minimized(&self) -> bool {
+        self.window_extent()
+            .map_or(false, |extent| extent.width == 0 || extent.height == 0)
+    }
+
+    #[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+    fn recreate_surface(&mut self) -> Result<(), RenderError> {
+        match (self.window.as_ref(), self.create_surface.as_ref()) {
+            (Some(window), Some(create_surface)) => {
+                self.surface = None;
+                self.surface = Some(create_surface(window));
+                Ok(())
+            },
+            _ => Err(RenderError::SurfaceLost),
+        }
+    }
+
+    /// The GL surface is the window's context, which can't be replaced.
+    #[cfg(not(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal")))]
+    fn recreate_surface(&mut self) -> Result<(), RenderError> {
+        Err(RenderError::SurfaceLost)
+    }
+
+    #[cfg(feature = "gl")]
+    fn resize_surface(&self) {
+        if let (Some(surface), Some(size)) = (self.surface.as_ref(), self.window_size) {
+            (self.resize_context)(surface, size.to_physical(self.hidpi_factor));
+        }
+    }
 }
 
 #[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
-pub fn create_backend(window_state: &mut WindowState) -> (BackendState<back::Backend>, back::Instance) {
+pub fn create_backend(
+    window_state: &mut WindowState,
+    config: &RenderingConfig
+) -> Result<(BackendState<back::Backend>, Rc<back::Instance>), RenderError> {
     let window = window_state
         .wb
         .take()
         .unwrap()
         .build(&window_state.events_loop)
-        .unwrap();
+        .map_err(|e| RenderError::Backend(format!("can't create window: {}", e)))?;
 
-    let instance = back::Instance::create(APP_TITLE, APP_VERSION);
+    let instance = Rc::new(back::Instance::create(APP_TITLE, APP_VERSION));
     let mut adapters = instance.enumerate_adapters();
-    let adapter = AdapterState::new(&mut adapters);
+    let adapter = AdapterState::new(&mut adapters, config.adapter.as_ref())?;
     let surface = instance.create_surface(&window);
-    (BackendState {adapter, surface, window}, instance)
+    let window_size = window.get_inner_size();
+    let hidpi_factor = window.get_hidpi_factor();
+    let surface_instance = Rc::clone(&instance);
+    Ok((
+        BackendState {
+            adapter,
+            surface: Some(surface),
+            window: Some(window),
+            create_surface: Some(Box::new(move |window| surface_instance.create_surface(window))),
+            window_size,
+            hidpi_factor
+        },
+        instance
+    ))
+}
+
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+/// Frames are rendered at `width` by `height` pixels.
+pub fn create_headless_backend(
+    config: &RenderingConfig,
+    width: u32,
+    height: u32
+) -> Result<(BackendState<back::Backend>, Rc<back::Instance>), RenderError> {
+    let instance = Rc::new(back::Instance::create(APP_TITLE, APP_VERSION));
+    let mut adapters = instance.enumerate_adapters();
+    let adapter = AdapterState::new(&mut adapters, config.adapter.as_ref())?;
+    Ok((
+        BackendState {
+            adapter,
+            surface: None,
+            window: None,
+            create_surface: None,
+            window_size: Some(LogicalSize::new(width as f64, height as f64)),
+            hidpi_factor: 1.0
+        },
+        instance
+    ))
+}
+
+/// Describes every adapter the backend offers, in the order
+/// `AdapterPreference::Index` refers to.
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+pub fn list_adapters() -> Vec<String> {
+    let instance = back::Instance::create(APP_TITLE, APP_VERSION);
+    adapter::describe_adapters(&instance.enumerate_adapters())
 }
 
 #[cfg(feature = "gl")]
-pub fn create_backend(window_state: &mut WindowState) -> (BackendState<back::Backend>, ()) {
+pub fn create_backend(
+    window_state: &mut WindowState,
+    config: &RenderingConfig
+) -> Result<(BackendState<back::Backend>, ()), RenderError> {
     let window = {
         let builder =
             back::config_context(back::glutin::ContextBuilder::new(),
@@ -101,55 +245,175 @@ pub fn create_backend(window_state: &mut WindowState) -> (BackendState<back::Bac
             window_state.wb.take().unwrap(),
             builder,
             &window_state.events_loop,
-        ).unwrap()
+        ).map_err(|e| RenderError::Backend(format!("can't create GL context: {}", e)))?
     };
 
+    let window_size = window.get_inner_size();
+    let hidpi_factor = window.get_hidpi_factor();
     let surface = back::Surface::from_window(window);
     let mut adapters = surface.enumerate_adapters();
-    (
+    Ok((
         BackendState {
-            adapter: AdapterState::new(&mut adapters),
-            surface
+            adapter: AdapterState::new(&mut adapters, config.adapter.as_ref())?,
+            surface: Some(surface),
+            window_size,
+            hidpi_factor,
+            resize_context: |surface, size| surface.get_window().resize(size)
         },
         (),
-    )
+    ))
 }
 
+/// How often events are checked while the window is minimized.
+const MINIMIZED_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
 pub struct RendererState<B: Backend> {
     device: Rc<RefCell<DeviceState<B>>>,
     swapchain: Option<SwapchainState<B>>,
     backend: BackendState<B>,
-    window: WindowState,
-    render_pass: RenderPassState<B>,
+    window: Option<WindowState>,
+    render_pass: Rc<RenderPassState<B>>,
+    desc_set_layout: DescriptorSetLayout<B>,
+    /// One for each of `Assets::pipelines`, in the same order. Dropped
+    /// before the registry, which holds their layout.
+    scene_pipelines: Vec<Rc<PipelineState<B>>>,
+    pipelines: PipelineRegistry<B>,
+    framebuffer: FramebufferState<B>,
+    model_buffers: Vec<ModelBuffers<B>>,
+    depth_image: DepthImage<B>,
+    color_image: ColorImage<B>,
+    offscreen: Option<OffscreenState<B>>,
+    materials: MaterialsState<B>,
+    scene: Scene,
+    uniform_desc_pool: Option<B::DescriptorPool>,
+    uniform_buffers: Vec<UniformBuffer<B>>,
+    commandbuffer: CommandBufferState<B>,
+    uploads: UploadManager<B>,
+    viewport: pso::Viewport,
+    camera: Camera,
+    fixed_uniforms: Option<UniformBufferObject>,
+    config: RenderingConfig,
+}
+
+/// Everything created from the logical device, so it can all be replaced
+/// when the device is lost.
+struct DeviceResources<B: Backend> {
+    device: Rc<RefCell<DeviceState<B>>>,
+    swapchain: Option<SwapchainState<B>>,
+    offscreen: Option<OffscreenState<B>>,
+    render_pass: Rc<RenderPassState<B>>,
     desc_set_layout: DescriptorSetLayout<B>,
-    pipeline: PipelineState<B>,
+    /// One for each of `Assets::pipelines`, in the same order. Dropped
+    /// before the registry, which holds their layout.
+    scene_pipelines: Vec<Rc<PipelineState<B>>>,
+    pipelines: PipelineRegistry<B>,
     framebuffer: FramebufferState<B>,
-    vertex_buffer: VertexBuffer<B>,
-    index_buffer: IndexBuffer<B>,
+    model_buffers: Vec<ModelBuffers<B>>,
     depth_image: DepthImage<B>,
     color_image: ColorImage<B>,
-    texture: Texture<B>,
+    materials: MaterialsState<B>,
     uniform_desc_pool: Option<B::DescriptorPool>,
     uniform_buffers: Vec<UniformBuffer<B>>,
     commandbuffer: CommandBufferState<B>,
+    uploads: UploadManager<B>,
     viewport: pso::Viewport,
 }
 
 impl<B: Backend> RendererState<B> {
 
-    pub unsafe fn new(mut backend: BackendState<B>, window: WindowState) -> Self {
+    /// `window` is only used for event handling and should be `None`
+    /// for a headless backend.
+    pub unsafe fn new(
+        mut backend: BackendState<B>,
+        window: Option<WindowState>,
+        scene: Scene,
+        config: &RenderingConfig
+    ) -> Result<Self, RenderError> {
+        backend.adapter.max_samples = config.msaa_samples;
+
+        let DeviceResources {
+            device,
+            swapchain,
+            offscreen,
+            render_pass,
+            desc_set_layout,
+            pipelines,
+            scene_pipelines,
+            framebuffer,
+            model_buffers,
+            depth_image,
+            color_image,
+            materials,
+            uniform_desc_pool,
+            uniform_buffers,
+            commandbuffer,
+            uploads,
+            viewport
+        } = RendererState::create_device_resources(&mut backend, &scene, config)?;
+
+        Ok(RendererState {
+            device,
+            swapchain,
+            backend,
+            window,
+            render_pass,
+            desc_set_layout,
+            pipelines,
+            scene_pipelines,
+            framebuffer,
+            model_buffers,
+            depth_image,
+            color_image,
+            offscreen,
+            materials,
+            scene,
+            uniform_desc_pool,
+            uniform_buffers,
+            commandbuffer,
+            uploads,
+            viewport,
+            camera: Camera::new(),
+            fixed_uniforms: None,
+            config: config.clone()
+        })
+    }
+
+    /// Opens the device and creates everything the scene is drawn with.
+    unsafe fn create_device_resources(
+        backend: &mut BackendState<B>,
+        scene: &Scene,
+        config: &RenderingConfig
+    ) -> Result<DeviceResources<B>, RenderError> {
         let device = Rc::new(RefCell::new(DeviceState::new(
-            backend.adapter.adapter.take().unwrap(),
-            &backend.surface,
-        )));
+            &backend.adapter.adapter,
+            backend.surface.as_ref(),
+        )?));
+
+        let (mut swapchain, offscreen) = if backend.is_headless() {
+            let extent = backend.window_extent().unwrap_or(Extent2D {
+                width: DEFAULT_WIDTH,
+                height: DEFAULT_HEIGHT
+            });
+            (None, Some(OffscreenState::new(
+                Rc::clone(&device),
+                &backend.adapter,
+                extent.width,
+                extent.height,
+                config.srgb
+            )?))
+        } else {
+            (Some(SwapchainState::new(backend, Rc::clone(&device), config)?), None)
+        };
 
-        let mut swapchain = Some(SwapchainState::new(&mut backend, Rc::clone(&device)));
+        let (format, extent) = RendererState::target_format_and_extent(
+            swapchain.as_ref(), offscreen.as_ref());
 
-        let render_pass = RenderPassState::new(
+        let render_pass = Rc::new(RenderPassState::new(
             Rc::clone(&device),
             &backend.adapter,
-            swapchain.as_ref().unwrap(),
-        );
+            format,
+            RendererState::resolve_layout(offscreen.as_ref()),
+        )?);
 
         let desc_set_layout = DescriptorSetLayout::new(
             Rc::clone(&device),
@@ -160,216 +424,250 @@ impl<B: Backend> RendererState<B> {
                     count: 1,
                     stage_flags: pso::ShaderStageFlags::VERTEX,
                     immutable_samplers: false,
-                },
-                pso::DescriptorSetLayoutBinding {
-                    binding: 1,
-                    ty: pso::DescriptorType::CombinedImageSampler,
-                    count: 1,
-                    stage_flags: pso::ShaderStageFlags::FRAGMENT,
-                    immutable_samplers: false
                 }
-            ]);
+            ])?;
+
+        // everything is uploaded by one submission, waited for below
+        let mut uploads = UploadManager::new(Rc::clone(&device), &backend.adapter)?;
 
-        let pipeline = PipelineState::new(
+        let materials = MaterialsState::new(
             Rc::clone(&device),
             &backend.adapter,
-            vec![desc_set_layout.get_layout()],
-            render_pass.render_pass.as_ref().unwrap(),
-            swapchain.as_ref().unwrap()
-        );
+            &mut uploads,
+            &scene.assets.textures,
+            config.srgb
+        )?;
 
-        let mut staging_command_pool = device
-            .borrow()
-            .device
-            .create_command_pool_typed(
-                &device.borrow().queues,
-                CommandPoolCreateFlags::TRANSIENT,
-            )
-            .expect("Can't create command pool");
+        let mut pipelines = PipelineRegistry::new(
+            Rc::clone(&device),
+            vec![desc_set_layout.get_layout(), materials.get_layout()],
+            Rc::clone(&render_pass),
+            PipelineCacheState::new(
+                Rc::clone(&device),
+                &backend.adapter.adapter.info,
+                config.pipeline_cache
+            )?
+        )?;
+
+        let scene_pipelines = RendererState::create_scene_pipelines(
+            &mut pipelines, &scene.assets.pipelines)?;
 
         let color_image = ColorImage::new(
             Rc::clone(&device),
             &backend.adapter,
-            swapchain.as_ref().unwrap(),
-            &mut staging_command_pool
-        );
+            extent.width, extent.height,
+            format,
+            &mut uploads
+        )?;
 
 
-        let depth_image = {
-            let width = swapchain.as_ref().unwrap().extent.width;
-            let height = swapchain.as_ref().unwrap().extent.height;
-            DepthImage::new(
+        let depth_image = DepthImage::new(
+            Rc::clone(&device),
+            &backend.adapter,
+            extent.width, extent.height,
+            &mut uploads
+        )?;
+
+        let framebuffer = match offscreen.as_ref() {
+            Some(offscreen) => FramebufferState::new_offscreen(
                 Rc::clone(&device),
-                &backend.adapter,
-                width, height,
-                &mut staging_command_pool
+                &render_pass,
+                offscreen,
+                &color_image,
+                &depth_image
+            ),
+            None => FramebufferState::new(
+                Rc::clone(&device),
+                &render_pass,
+                swapchain.as_mut().unwrap(),
+                &color_image,
+                &depth_image
             )
-        };
+        }?;
 
-        let mut framebuffer = FramebufferState::new(
-            Rc::clone(&device),
-            &render_pass,
-            swapchain.as_mut().unwrap(),
-            &color_image,
-            &depth_image
-        );
-
-        let model = primitives::Model::load(std::path::Path::new("models/chalet.obj"));
+        let model_buffers = scene.assets.models
+            .iter()
+            .map(|model| ModelBuffers::new(
+                Rc::clone(&device),
+                &mut uploads,
+                model
+            ))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let vertex_buffer = VertexBuffer::new::<primitives::Vertex>(
-            Rc::clone(&device),
-            &mut staging_command_pool,
-            &model.vertices,
-            &backend.adapter.memory_types,
-        );
+        let num_buffers = framebuffer.framebuffers.as_ref().unwrap().len();
 
-        let index_buffer = IndexBuffer::new(
+        let (uniform_desc_pool, uniform_buffers) = RendererState::create_uniform_buffers(
             Rc::clone(&device),
-            &mut staging_command_pool,
-            &model.indicies,
-            &backend.adapter.memory_types
-        );
-
-        let img = image::load(Cursor::new(&images::CHALET_JPG_DATA[..]), image::JPEG)
-            .unwrap()
-            .to_rgba();
+            &desc_set_layout,
+            num_buffers
+        )?;
 
-        let texture = Texture::new(
-            Rc::clone(&device),
-            &backend.adapter,
-            &mut staging_command_pool,
-            &img
-        );
+        uploads.finish()?;
 
-        // TODO: all this in one constructor
+        let commandbuffer = CommandBufferState::new(Rc::clone(&device), config)?;
 
-        let num_buffers = framebuffer.framebuffers.as_ref().unwrap().len();
+        let viewport = RendererState::create_viewport(&extent);
 
-        let mut uniform_desc_pool = device
-            .borrow()
-            .device
-            .create_descriptor_pool(
-                num_buffers,
-                &[pso::DescriptorRangeDesc {
-                    ty: pso::DescriptorType::UniformBuffer,
-                    count: num_buffers,
-                }, pso::DescriptorRangeDesc {
-                    ty: pso::DescriptorType::CombinedImageSampler,
-                    count: num_buffers
-                }],
-                pso::DescriptorPoolCreateFlags::empty(),
-            )
-            .ok();
+        debug!("{:?}", device.borrow().allocator.stats());
 
-        let uniform_desc_sets = desc_set_layout.create_desc_sets(
-            uniform_desc_pool.as_mut().unwrap(),
-            num_buffers
-        );
-        let mut uniform_buffers = Vec::with_capacity(num_buffers);
-        for desc in uniform_desc_sets {
-            texture.write_descriptor_set(
-                &mut device.borrow_mut().device,
-                &desc,
-                1
-            );
+        Ok(DeviceResources {
+            device,
+            swapchain,
+            offscreen,
+            render_pass,
+            desc_set_layout,
+            pipelines,
+            scene_pipelines,
+            framebuffer,
+            model_buffers,
+            depth_image,
+            color_image,
+            materials,
+            uniform_desc_pool,
+            uniform_buffers,
+            commandbuffer,
+            uploads,
+            viewport
+        })
+    }
 
-            let ub = UniformBuffer::new::<primitives::UniformBufferObject>(
-                Rc::clone(&device),
-                &backend.adapter.memory_types,
-                desc,
-                0
-            );
-            uniform_buffers.push(ub);
+    /// Opens the adapter again after the device was lost and rebuilds
+    /// everything created from it out of the scene kept in host memory.
+    fn recreate_device(&mut self) -> Result<(), RenderError> {
+        warn!("Device lost, recreating it");
+        // the surface can only have one swapchain at a time
+        self.swapchain.take();
+        self.uniform_buffers.clear();
+        if let Some(uniform_desc_pool) = self.uniform_desc_pool.take() {
+            unsafe {
+                self.device.borrow().device.destroy_descriptor_pool(uniform_desc_pool);
+            }
         }
 
-        device.borrow().device.destroy_command_pool(
-            staging_command_pool.into_raw());
-
-        // ------------------
-
-        let commandbuffer = CommandBufferState::new(
-            Rc::clone(&device),
-            &mut framebuffer,
-            &render_pass,
-            swapchain.as_ref().unwrap(),
-            &pipeline,
-            &vertex_buffer,
-            &index_buffer,
-            model.indicies.len() as _,
-            &uniform_buffers
-        );
-
-        let viewport = RendererState::create_viewport(
-            swapchain.as_ref().unwrap());
-
-        RendererState {
+        let DeviceResources {
             device,
             swapchain,
-            backend,
-            window,
+            offscreen,
             render_pass,
             desc_set_layout,
-            pipeline,
+            pipelines,
+            scene_pipelines,
             framebuffer,
-            vertex_buffer,
-            index_buffer,
+            model_buffers,
             depth_image,
             color_image,
-            texture,
+            materials,
             uniform_desc_pool,
             uniform_buffers,
             commandbuffer,
+            uploads,
             viewport
+        } = unsafe {
+            RendererState::create_device_resources(&mut self.backend, &self.scene, &self.config)?
+        };
+
+        // the old resources are destroyed along with the old device as they
+        // are replaced
+        self.device = device;
+        self.swapchain = swapchain;
+        self.offscreen = offscreen;
+        self.render_pass = render_pass;
+        self.desc_set_layout = desc_set_layout;
+        self.scene_pipelines = scene_pipelines;
+        self.pipelines = pipelines;
+        self.framebuffer = framebuffer;
+        self.model_buffers = model_buffers;
+        self.depth_image = depth_image;
+        self.color_image = color_image;
+        self.materials = materials;
+        self.uniform_desc_pool = uniform_desc_pool;
+        self.uniform_buffers = uniform_buffers;
+        self.commandbuffer = commandbuffer;
+        self.uploads = uploads;
+        self.viewport = viewport;
+        Ok(())
+    }
+
+    /// Replaces a lost surface and the swapchain created for it.
+    fn recreate_surface(&mut self) -> Result<(), RenderError> {
+        warn!("Surface lost, recreating it");
+        self.device.borrow().device.wait_idle()?;
+        // the swapchain has to go before its surface
+        self.swapchain.take();
+        self.backend.recreate_surface()?;
+        self.recreate_swapchain()
+    }
+
+    /// Rebuilds whatever `error` invalidated, or returns it if that isn't
+    /// possible.
+    fn recover(&mut self, error: RenderError) -> Result<(), RenderError> {
+        match error {
+            RenderError::SurfaceLost => self.recreate_surface(),
+            RenderError::DeviceLost => self.recreate_device(),
+            e => Err(e),
         }
     }
 
-    fn recreate_swapchain(&mut self) {
-        self.device.borrow().device.wait_idle().unwrap();
+    /// Rebuilds everything that depends on the swapchain images or their
+    /// size. Meshes, textures, descriptor set layouts and, unless the
+    /// format changed, the render pass and pipelines are kept.
+    fn recreate_swapchain(&mut self) -> Result<(), RenderError> {
+        self.device.borrow().device.wait_idle()?;
 
-        self.swapchain.take().unwrap();
+        self.swapchain.take();
+        self.commandbuffer.reset_images_in_flight();
 
-        self.swapchain =
-            Some(unsafe { SwapchainState::new(&mut self.backend, Rc::clone(&self.device)) });
+        #[cfg(feature = "gl")]
+        self.backend.resize_surface();
 
-        self.render_pass = unsafe {
-            RenderPassState::new(
-                Rc::clone(&self.device),
-                &self.backend.adapter,
-                self.swapchain.as_ref().unwrap()
-            )
-        };
+        self.swapchain =
+            Some(unsafe {
+                SwapchainState::new(&mut self.backend, Rc::clone(&self.device), &self.config)?
+            });
 
-        let mut staging_command_pool = unsafe {
-            self.device
-                .borrow()
-                .device
-                .create_command_pool_typed(
-                    &self.device.borrow().queues,
-                    CommandPoolCreateFlags::TRANSIENT,
-                )
-                .expect("Can't create command pool")
-        };
+        let (format, extent) = RendererState::target_format_and_extent(
+            self.swapchain.as_ref(), None);
+
+        if format != self.render_pass.format {
+            self.render_pass = Rc::new(unsafe {
+                RenderPassState::new(
+                    Rc::clone(&self.device),
+                    &self.backend.adapter,
+                    format,
+                    Layout::Present
+                )?
+            });
+            // pipelines are tied to a compatible render pass
+            self.pipelines.set_render_pass(Rc::clone(&self.render_pass));
+            self.scene_pipelines = unsafe {
+                RendererState::create_scene_pipelines(
+                    &mut self.pipelines, &self.scene.assets.pipelines)?
+            };
+        }
 
         self.color_image = unsafe {
             ColorImage::new(
                 Rc::clone(&self.device),
                 &self.backend.adapter,
-                self.swapchain.as_ref().unwrap(),
-                &mut staging_command_pool
-            )
+                extent.width, extent.height,
+                format,
+                &mut self.uploads
+            )?
         };
 
         self.depth_image = unsafe {
-            let width = self.swapchain.as_ref().unwrap().extent.width;
-            let height = self.swapchain.as_ref().unwrap().extent.height;
             DepthImage::new(
                 Rc::clone(&self.device),
                 &self.backend.adapter,
-                width, height,
-                &mut staging_command_pool
-            )
+                extent.width, extent.height,
+                &mut self.uploads
+            )?
         };
 
+        unsafe {
+            self.uploads.finish()?;
+        }
+
         self.framebuffer = unsafe {
             FramebufferState::new(
                 Rc::clone(&self.device),
@@ -377,161 +675,251 @@ impl<B: Backend> RendererState<B> {
                 self.swapchain.as_mut().unwrap(),
                 &self.color_image,
                 &self.depth_image
-            )
-        };
-
-        self.desc_set_layout = unsafe {
-            DescriptorSetLayout::new(
-            Rc::clone(&self.device),
-            vec![
-                pso::DescriptorSetLayoutBinding {
-                    binding: 0,
-                    ty: pso::DescriptorType::UniformBuffer,
-                    count: 1,
-                    stage_flags: pso::ShaderStageFlags::VERTEX,
-                    immutable_samplers: false,
-                },
-                pso::DescriptorSetLayoutBinding {
-                    binding: 1,
-                    ty: pso::DescriptorType::CombinedImageSampler,
-                    count: 1,
-                    stage_flags: pso::ShaderStageFlags::FRAGMENT,
-                    immutable_samplers: false
-                }
-            ])
-        };
-
-        self.pipeline = unsafe {
-            PipelineState::new(
-                Rc::clone(&self.device),
-                &self.backend.adapter,
-                vec![self.desc_set_layout.get_layout()],
-                self.render_pass.render_pass.as_ref().unwrap(),
-                self.swapchain.as_mut().unwrap(),
-            )
+            )?
         };
 
-        let model = primitives::Model::load(std::path::Path::new("models/chalet.obj"));
-
-        self.vertex_buffer = unsafe {
-            VertexBuffer::new::<primitives::Vertex>(
-                Rc::clone(&self.device),
-                &mut staging_command_pool,
-                &model.vertices,
-                &self.backend.adapter.memory_types,
-            )
-        };
+        // one uniform buffer per swapchain image, so they only need
+        // replacing if the driver handed back a different image count
+        let num_buffers = self.framebuffer.framebuffers.as_ref().unwrap().len();
+        if num_buffers != self.uniform_buffers.len() {
+            self.uniform_buffers.clear();
+            unsafe {
+                self.device
+                    .borrow()
+                    .device
+                    .destroy_descriptor_pool(self.uniform_desc_pool.take().unwrap());
+
+                let (uniform_desc_pool, uniform_buffers) = RendererState::create_uniform_buffers(
+                    Rc::clone(&self.device),
+                    &self.desc_set_layout,
+                    num_buffers
+                )?;
+                self.uniform_desc_pool = uniform_desc_pool;
+                self.uniform_buffers = uniform_buffers;
+            }
+        }
 
-        self.index_buffer = unsafe {
-            IndexBuffer::new(
-                Rc::clone(&self.device),
-                &mut staging_command_pool,
-                &model.indicies,
-                &self.backend.adapter.memory_types,
-            )
-        };
+        self.viewport = RendererState::create_viewport(&extent);
+        Ok(())
+    }
 
-        let img = image::load(Cursor::new(&images::CHALET_JPG_DATA[..]), image::JPEG)
-            .unwrap()
-            .to_rgba();
+    /// One uniform buffer, with its own descriptor set, per framebuffer.
+    unsafe fn create_uniform_buffers(
+        device: Rc<RefCell<DeviceState<B>>>,
+        desc_set_layout: &DescriptorSetLayout<B>,
+        num_buffers: usize
+    ) -> Result<(Option<B::DescriptorPool>, Vec<UniformBuffer<B>>), RenderError> {
+        let mut uniform_desc_pool = device
+            .borrow()
+            .device
+            .create_descriptor_pool(
+                num_buffers,
+                &[pso::DescriptorRangeDesc {
+                    ty: pso::DescriptorType::UniformBuffer,
+                    count: num_buffers,
+                }],
+                pso::DescriptorPoolCreateFlags::empty(),
+            )?;
+
+        let uniform_buffers = desc_set_layout
+            .create_desc_sets(&mut uniform_desc_pool, num_buffers)
+            .and_then(|uniform_desc_sets| {
+                uniform_desc_sets
+                    .into_iter()
+                    .map(|desc| {
+                        UniformBuffer::new::<primitives::UniformBufferObject>(
+                            Rc::clone(&device),
+                            desc,
+                            0
+                        )
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            });
 
-        self.texture = unsafe {
-            Texture::new(
-                Rc::clone(&self.device),
-                &self.backend.adapter,
-                &mut staging_command_pool,
-                &img
-            )
-        };
+        match uniform_buffers {
+            Ok(uniform_buffers) => Ok((Some(uniform_desc_pool), uniform_buffers)),
+            Err(e) => {
+                device.borrow().device.destroy_descriptor_pool(uniform_desc_pool);
+                Err(e)
+            }
+        }
+    }
 
-        unsafe {
-            self.device.borrow().device.destroy_command_pool(
-                staging_command_pool.into_raw());
-            self.device
-                .borrow()
-                .device
-                .destroy_descriptor_pool(self.uniform_desc_pool.take().unwrap());
-
-            let num_buffers = self.framebuffer.framebuffers.as_ref().unwrap().len();
-            self.uniform_desc_pool = self.device
-                .borrow()
-                .device
-                .create_descriptor_pool(
-                    num_buffers,
-                    &[pso::DescriptorRangeDesc {
-                        ty: pso::DescriptorType::UniformBuffer,
-                        count: num_buffers,
-                    }, pso::DescriptorRangeDesc {
-                        ty: pso::DescriptorType::CombinedImageSampler,
-                        count: num_buffers
-                    }],
-                    pso::DescriptorPoolCreateFlags::empty(),
-                )
-                .ok();
+    /// Gets a pipeline for each of `descs`. One the device can't create
+    /// is replaced by the default pipeline, which comes first.
+    unsafe fn create_scene_pipelines(
+        pipelines: &mut PipelineRegistry<B>,
+        descs: &[PipelineDesc]
+    ) -> Result<Vec<Rc<PipelineState<B>>>, RenderError> {
+        let mut scene_pipelines: Vec<Rc<PipelineState<B>>> = Vec::with_capacity(descs.len());
+        for desc in descs {
+            let pipeline = match pipelines.get(desc) {
+                Ok(pipeline) => pipeline,
+                Err(RenderError::Unsupported(reason)) if !scene_pipelines.is_empty() => {
+                    warn!("Drawing with the default pipeline instead: {}", reason);
+                    Rc::clone(&scene_pipelines[0])
+                },
+                Err(e) => return Err(e),
+            };
+            scene_pipelines.push(pipeline);
+        }
+        Ok(scene_pipelines)
+    }
 
-            let uniform_desc_sets = self.desc_set_layout.create_desc_sets(
-                self.uniform_desc_pool.as_mut().unwrap(),
-                num_buffers
-            );
+    /// One draw call per mesh of every object in the scene, grouped by
+    /// pipeline. Blended meshes come last so they blend over everything
+    /// opaque.
+    fn draw_calls<'a>(
+        scene: &Scene,
+        model_buffers: &'a [ModelBuffers<B>],
+        materials: &'a MaterialsState<B>,
+        scene_pipelines: &'a [Rc<PipelineState<B>>]
+    ) -> Vec<DrawCall<'a, B>> {
+        let assets = &scene.assets;
+        let mut draw_calls: Vec<DrawCall<'a, B>> = scene.objects()
+            .iter()
+            .flat_map(|object| {
+                let buffers = &model_buffers[object.model()];
+                assets.models[object.model()].meshes
+                    .iter()
+                    .map(move |mesh| DrawCall {
+                        pipeline: &scene_pipelines[assets.mesh_pipeline(object.model(), mesh)],
+                        vertex_buffer: &buffers.vertex_buffer,
+                        index_buffer: &buffers.index_buffer,
+                        indicies: mesh.indicies.clone(),
+                        material_set: materials.get_descriptor_set(
+                            assets.mesh_texture(object.model(), mesh)),
+                        transform: object.transform,
+                    })
+            })
+            .collect();
+        // stable, so each pipeline's calls keep the scene's order
+        draw_calls.sort_by_key(|draw_call| (
+            draw_call.pipeline.desc.blend != BlendMode::Off,
+            draw_call.pipeline as *const PipelineState<B>
+        ));
+        draw_calls
+    }
 
-            self.uniform_buffers = uniform_desc_sets
-                .into_iter()
-                .map(|desc| {
-                    self.texture.write_descriptor_set(&mut self.device.borrow_mut().device, &desc, 1);
-                    UniformBuffer::new::<primitives::UniformBufferObject>(
-                        Rc::clone(&self.device),
-                        &self.backend.adapter.memory_types,
-                        desc,
-                        0
-                    )
-                }).collect();
+    fn target_format_and_extent(
+        swapchain: Option<&SwapchainState<B>>,
+        offscreen: Option<&OffscreenState<B>>
+    ) -> (Format, Extent) {
+        match (swapchain, offscreen) {
+            (Some(swapchain), _) => (swapchain.format, swapchain.extent),
+            (None, Some(offscreen)) => (offscreen.format, offscreen.extent),
+            (None, None) => unreachable!("renderer has no render target"),
         }
+    }
 
-        self.commandbuffer = unsafe {
-            CommandBufferState::new(
-                Rc::clone(&self.device),
-                &mut self.framebuffer,
-                &self.render_pass,
-                self.swapchain.as_ref().unwrap(),
-                &self.pipeline,
-                &self.vertex_buffer,
-                &self.index_buffer,
-                model.indicies.len() as _,
-                &self.uniform_buffers
-            )
-        };
-
-        self.viewport = RendererState::create_viewport(
-            self.swapchain.as_ref().unwrap());
+    /// Swapchain images are handed to the presentation engine, the
+    /// offscreen image is copied back to the host after each frame.
+    fn resolve_layout(offscreen: Option<&OffscreenState<B>>) -> Layout {
+        match offscreen {
+            Some(_) => Layout::TransferSrcOptimal,
+            None => Layout::Present,
+        }
     }
 
-    fn create_viewport(swapchain: &SwapchainState<B>) -> pso::Viewport {
+    fn create_viewport(extent: &Extent) -> pso::Viewport {
         pso::Viewport {
             rect: pso::Rect {
                 x: 0,
                 y: 0,
-                w: swapchain.extent.width as i16,
-                h: swapchain.extent.height as i16,
+                w: extent.width as i16,
+                h: extent.height as i16,
             },
             depth: 0.0..1.0,
         }
     }
 
-    fn draw_frame(&mut self, start_time: &std::time::Instant, frame_number: usize) -> bool {
-        let current_frame = frame_number % commandbuffer::MAX_FRAMES_IN_FLIGHT;
-        let acquire_semaphore = &self.commandbuffer
-            .acquire_semaphores.as_ref().unwrap()[current_frame];
-        let present_semaphore = &self.commandbuffer
-            .present_semaphores.as_ref().unwrap()[current_frame];
-        let fence = &self.commandbuffer
-            .fences.as_ref().unwrap()[current_frame];
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.device.borrow().allocator.stats()
+    }
+
+    /// Width and height of the current render target.
+    pub fn extent(&self) -> (u32, u32) {
+        let (_, extent) = RendererState::target_format_and_extent(
+            self.swapchain.as_ref(), self.offscreen.as_ref());
+        (extent.width, extent.height)
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    /// Changes are drawn from the next frame on.
+    pub fn scene_mut(&mut self) -> &mut Scene {
+        &mut self.scene
+    }
+
+    /// Replaces the camera driven view with a fixed transform, so
+    /// rendered frames are reproducible.
+    pub fn set_fixed_uniforms(&mut self, ubo: UniformBufferObject) {
+        self.fixed_uniforms = Some(ubo);
+    }
+
+    fn uniform_buffer_object(&self) -> UniformBufferObject {
+        if let Some(ubo) = self.fixed_uniforms {
+            return ubo;
+        }
+
+        let (_, extent) = RendererState::target_format_and_extent(
+            self.swapchain.as_ref(), self.offscreen.as_ref());
+        let rad45 = {
+            glm::radians(&glm::vec1(45.0))[0]
+        };
+        let mut ubo = UniformBufferObject {
+            model: glm::Mat4::identity(),
+            view: self.camera.view_matrix(),
+            proj: glm::perspective(
+                utils::ratio(extent.width, extent.height),
+                rad45,
+                0.1,
+                100.0)
+        };
+        ubo.proj[1 * 4 + 1] *= -1.0;
+        ubo
+    }
+
+    /// Records the command buffer of `current_frame` to draw the scene as
+    /// it is now into framebuffer `image`, whose uniform buffer it uses.
+    fn record_frame(&mut self, current_frame: usize, image: usize) {
+        let draw_calls = RendererState::draw_calls(
+            &self.scene, &self.model_buffers, &self.materials, &self.scene_pipelines);
+        unsafe {
+            self.commandbuffer.record(
+                current_frame,
+                &self.render_pass,
+                &self.framebuffer.framebuffers.as_ref().unwrap()[image],
+                &self.viewport,
+                self.pipelines.get_layout(),
+                self.uniform_buffers[image].get_descriptor_set(),
+                &draw_calls,
+                self.config.clear_color
+            );
+        }
+    }
+
+    /// Returns `Ok(false)` if the swapchain no longer matches the surface
+    /// and has to be recreated.
+    fn draw_frame(&mut self, frame_number: usize) -> Result<bool, RenderError> {
+        if self.offscreen.is_some() {
+            return self.draw_offscreen_frame();
+        }
+
+        let current_frame = frame_number % self.config.frames_in_flight;
 
         unsafe {
+            let fence = &self.commandbuffer
+                .fences.as_ref().unwrap()[current_frame];
             let device = &self.device.borrow().device;
-            device.wait_for_fence(&fence, !0).unwrap();
+            device.wait_for_fence(&fence, !0)?;
         }
 
         let frame: gfx_hal::SwapImageIndex = unsafe {
+            let acquire_semaphore = &self.commandbuffer
+                .acquire_semaphores.as_ref().unwrap()[current_frame];
             match self.swapchain
                 .as_mut()
                 .unwrap()
@@ -544,18 +932,25 @@ impl<B: Backend> RendererState<B> {
                     None)
             {
                 Ok((i, _)) => i,
-                Err(e) => {
-                    match e {
-                        gfx_hal::AcquireError::OutOfDate =>
-                            return false,
-                        _ => ()
-                    };
-                    panic!(e)
-                },
+                Err(gfx_hal::AcquireError::OutOfDate) => return Ok(false),
+                Err(e) => return Err(e.into()),
             }
         };
 
-        let current_cmd_buffer = &self.commandbuffer.command_buffers.as_ref().unwrap()[frame as usize];
+        // the image's uniform buffer may still be read by another frame
+        unsafe {
+            self.commandbuffer.wait_for_image(frame as usize, current_frame)?;
+        }
+
+        self.record_frame(current_frame, frame as usize);
+
+        let acquire_semaphore = &self.commandbuffer
+            .acquire_semaphores.as_ref().unwrap()[current_frame];
+        let present_semaphore = &self.commandbuffer
+            .present_semaphores.as_ref().unwrap()[current_frame];
+        let fence = &self.commandbuffer
+            .fences.as_ref().unwrap()[current_frame];
+        let current_cmd_buffer = &self.commandbuffer.command_buffers.as_ref().unwrap()[current_frame];
         let submission = Submission {
             command_buffers: std::iter::once(current_cmd_buffer),
             wait_semaphores: std::iter::once((&acquire_semaphore, pso::PipelineStage::COLOR_ATTACHMENT_OUTPUT)),
@@ -563,42 +958,15 @@ impl<B: Backend> RendererState<B> {
         };
 
         // update UBO
-        let time: f32 = utils::as_float_secs(&start_time.elapsed()) / 2.0;
-        let swapchain_extent = &self.swapchain
-            .as_ref()
-            .unwrap()
-            .extent;
-        let rad90 = {
-            glm::radians(&glm::vec1(90.0))[0]
-        };
-        let rad45 = {
-            glm::radians(&glm::vec1(45.0))[0]
-        };
-        let mut ubo = primitives::UniformBufferObject {
-            model: glm::rotate(
-                &glm::Mat4::identity(),
-                time * rad90,
-                &glm::vec3(0.0, 0.0, 1.0)),
-            view: glm::look_at(
-                &glm::vec3(2.0, 2.0, 2.0),
-                &glm::vec3(0.0, 0.0, 0.0),
-                &glm::vec3(0.0, 0.0, 1.0)),
-            proj: glm::perspective(
-                utils::ratio(swapchain_extent.width, swapchain_extent.height),
-                rad45,
-                0.1,
-                10.0)
-        };
-        ubo.proj[1 * 4 + 1] *= -1.0;
-
+        let ubo = self.uniform_buffer_object();
         let uniform_buffer = &mut self.uniform_buffers[frame as usize];
-        uniform_buffer.update_data(0, &[ubo]);
+        uniform_buffer.update_data(0, &[ubo])?;
 
         unsafe {
 
             {
                 let device = &self.device.borrow().device;
-                device.reset_fence(&fence).unwrap();
+                device.reset_fence(&fence)?;
             }
 
             let queue = &mut self.device.borrow_mut().queues.queues[0];
@@ -619,71 +987,181 @@ impl<B: Backend> RendererState<B> {
             {
                 Ok(suboptimal) => {
                     if suboptimal.is_some() {
-                        return false;
+                        return Ok(false);
                     }
                 },
-                Err(e) => {
-                    match e {
-                        gfx_hal::window::PresentError::OutOfDate =>
-                            return false,
-                        _ => ()
-                    };
-                    panic!(e)
-                }
+                Err(gfx_hal::window::PresentError::OutOfDate) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Headless counterpart of the swapchain path above: there is no image
+    /// to acquire or present, so the first command buffer is submitted
+    /// directly and `capture_frame` reads the result back.
+    fn draw_offscreen_frame(&mut self) -> Result<bool, RenderError> {
+        unsafe {
+            let fence = &self.commandbuffer
+                .fences.as_ref().unwrap()[0];
+            let device = &self.device.borrow().device;
+            device.wait_for_fence(&fence, !0)?;
+        }
+
+        self.record_frame(0, 0);
+
+        let fence = &self.commandbuffer
+            .fences.as_ref().unwrap()[0];
+        let ubo = self.uniform_buffer_object();
+        self.uniform_buffers[0].update_data(0, &[ubo])?;
+
+        unsafe {
+            {
+                let device = &self.device.borrow().device;
+                device.reset_fence(&fence)?;
             }
+
+            let current_cmd_buffer = &self.commandbuffer.command_buffers.as_ref().unwrap()[0];
+            let queue = &mut self.device.borrow_mut().queues.queues[0];
+            queue.submit_without_semaphores(std::iter::once(current_cmd_buffer), Some(fence));
         }
 
-        return true;
+        Ok(true)
     }
 
-    pub fn mainloop(&mut self) {
+    /// Waits for the last offscreen frame and copies it back to the host.
+    fn capture_frame(&mut self) -> Result<image::RgbaImage, RenderError> {
+        let fence = &self.commandbuffer
+            .fences.as_ref().unwrap()[0];
+        unsafe {
+            self.device.borrow().device.wait_for_fence(&fence, !0)?;
+            self.offscreen
+                .as_mut()
+                .expect("capture_frame requires a headless renderer")
+                .read_pixels()
+        }
+    }
+
+    /// Draws a single headless frame and returns its pixels.
+    pub fn render_frame(&mut self) -> Result<image::RgbaImage, RenderError> {
+        self.draw_frame(0)?;
+        self.capture_frame()
+    }
+
+    pub fn render_to_file(&mut self, path: &Path) -> Result<(), RenderError> {
+        Ok(self.render_frame()?.save(path)?)
+    }
+
+    pub fn mainloop(&mut self) -> Result<(), RenderError> {
         let mut running = true;
         let mut frame_number = 0;
-        let start_time = std::time::Instant::now();
+        let mut resized = false;
+        let mut cycle_present_mode = false;
+        let mut last_frame = std::time::Instant::now();
         while running {
-            self.window.events_loop.poll_events(|event| {
-                if let winit::Event::WindowEvent { event, .. } = event {
-                    #[allow(unused_variables)]
-                    match event {
-                        winit::WindowEvent::KeyboardInput {
-                            input:
-                            winit::KeyboardInput {
-                                virtual_keycode: Some(winit::VirtualKeyCode::Escape),
-                                ..
+            let camera = &mut self.camera;
+            let backend = &mut self.backend;
+            self.window
+                .as_mut()
+                .expect("mainloop requires a window")
+                .events_loop
+                .poll_events(|event| {
+                    if let winit::Event::WindowEvent { event, .. } = event {
+                        if camera.handle_event(&event) {
+                            return;
+                        }
+                        #[allow(unused_variables)]
+                        match event {
+                            winit::WindowEvent::KeyboardInput {
+                                input:
+                                winit::KeyboardInput {
+                                    virtual_keycode: Some(winit::VirtualKeyCode::Escape),
+                                    ..
+                                },
+                            ..
+                            }
+                            | winit::WindowEvent::CloseRequested => running = false,
+                            winit::WindowEvent::KeyboardInput {
+                                input:
+                                winit::KeyboardInput {
+                                    state: winit::ElementState::Pressed,
+                                    virtual_keycode: Some(winit::VirtualKeyCode::V),
+                                    ..
+                                },
+                            ..
+                            } => cycle_present_mode = true,
+                            winit::WindowEvent::Resized(size) => {
+                                debug!("RESIZED {:?}", size);
+                                backend.window_size = Some(size);
+                                resized = true;
                             },
-                        ..
+                            winit::WindowEvent::HiDpiFactorChanged(factor) => {
+                                debug!("HIDPI FACTOR CHANGED {:?}", factor);
+                                backend.hidpi_factor = factor;
+                                resized = true;
+                            },
+                            _ => (),
                         }
-                        | winit::WindowEvent::CloseRequested => running = false,
-                        winit::WindowEvent::Resized(dims) => {
-                            // TODO: is this the same as glfwSetFramebufferSizeCallback ?
-                            // Do we need 'Handling resizes explicitly' section?
-                            debug!("RESIZED {:?}", dims);
-                        },
-                        _ => (),
                     }
-                }
-            });
-            if self.draw_frame(&start_time, frame_number) == false {
-                self.recreate_swapchain();
+                });
+
+            let now = std::time::Instant::now();
+            self.camera.update(utils::as_float_secs(&(now - last_frame)));
+            last_frame = now;
+
+            if self.backend.is_minimized() {
+                std::thread::sleep(MINIMIZED_POLL_INTERVAL);
                 continue;
-            };
+            }
+
+            if cycle_present_mode {
+                cycle_present_mode = false;
+                if let Some(swapchain) = self.swapchain.as_ref() {
+                    self.config.present_mode = swapchain.next_present_mode();
+                    info!("Switching to {:?} present mode", self.config.present_mode);
+                    resized = true;
+                }
+            }
+
+            if resized {
+                resized = false;
+                self.recreate_swapchain().or_else(|e| self.recover(e))?;
+            }
+
+            match self.draw_frame(frame_number) {
+                Ok(true) => (),
+                Ok(false) => {
+                    self.recreate_swapchain().or_else(|e| self.recover(e))?;
+                    continue;
+                },
+                Err(e) => {
+                    self.recover(e)?;
+                    continue;
+                }
+            }
             frame_number += 1;
             if frame_number % 60 == 0 {
                 println!("...");
             }
         }
-        self.device.borrow().device.wait_idle().unwrap();
+        self.device.borrow().device.wait_idle()?;
+        Ok(())
     }
 }
 
 impl<B: Backend> Drop for RendererState<B> {
     fn drop(&mut self) {
-        self.device.borrow().device.wait_idle().unwrap();
+        // a lost device has nothing left to wait for
+        let _ = self.device.borrow().device.wait_idle();
         unsafe {
-            self.device
-                .borrow()
-                .device
-                .destroy_descriptor_pool(self.uniform_desc_pool.take().unwrap());
+            // gone if recreating the device failed
+            if let Some(uniform_desc_pool) = self.uniform_desc_pool.take() {
+                self.device
+                    .borrow()
+                    .device
+                    .destroy_descriptor_pool(uniform_desc_pool);
+            }
             self.swapchain.take();
         }
     }
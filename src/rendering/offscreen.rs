@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use log::debug;
+use gfx_hal::{Backend, CommandPool, Device, Graphics};
+use gfx_hal::buffer::Access as BufferAccess;
+use gfx_hal::command;
+use gfx_hal::format::{AsFormat, Aspects, Format, Rgba8Srgb, Rgba8Unorm, Swizzle};
+use gfx_hal::image::{Access, Extent, Kind, Layout, Offset, Size,
+                     SubresourceLayers, Tiling, Usage as ImageUsage, ViewKind};
+use gfx_hal::memory::{Barrier, Dependencies as MemoryDependencies,
+                      Properties as MemoryProperties};
+use gfx_hal::pool::CommandPoolCreateFlags;
+use gfx_hal::pso::PipelineStage;
+
+use super::adapter::AdapterState;
+use super::allocator::Allocation;
+use super::buffer::ReadbackBuffer;
+use super::constants::COLOR_RANGE;
+use super::device::DeviceState;
+use super::error::RenderError;
+use super::images::create_image;
+
+/// Stands in for the `SwapchainState` when rendering without a window:
+/// the render pass resolves into `image`, which is then copied back to
+/// host memory.
+pub(super) struct OffscreenState<B: Backend> {
+    pub(super) extent: Extent,
+    pub(super) format: Format,
+    pub(super) image_view: Option<B::ImageView>,
+    image: Option<B::Image>,
+    memory: Option<Allocation>,
+    readback: Option<ReadbackBuffer<B>>,
+    command_pool: Option<CommandPool<B, Graphics>>,
+    device: Rc<RefCell<DeviceState<B>>>,
+}
+
+impl<B: Backend> OffscreenState<B> {
+    pub(super) unsafe fn new(
+        device_ptr: Rc<RefCell<DeviceState<B>>>,
+        adapter: &AdapterState<B>,
+        width: u32,
+        height: u32,
+        srgb: bool
+    ) -> Result<Self, RenderError> {
+        // PNGs are sRGB encoded, so an sRGB target can be saved as is
+        let format = if srgb { Rgba8Srgb::SELF } else { Rgba8Unorm::SELF };
+        let extent = Extent { width, height, depth: 1 };
+        debug!("Offscreen extent: {:?} format: {:?}", extent, format);
+
+        let (image, memory) = create_image(
+            &mut device_ptr.borrow_mut(),
+            Kind::D2(width as Size, height as Size, 1, 1),
+            format,
+            Tiling::Optimal,
+            ImageUsage::COLOR_ATTACHMENT | ImageUsage::TRANSFER_SRC,
+            MemoryProperties::DEVICE_LOCAL,
+            1
+        )?;
+
+        let image_view = device_ptr
+            .borrow()
+            .device
+            .create_image_view(
+                &image,
+                ViewKind::D2,
+                format,
+                Swizzle::NO,
+                COLOR_RANGE.clone()
+            )?;
+
+        let readback = ReadbackBuffer::new(
+            Rc::clone(&device_ptr),
+            &adapter,
+            width,
+            height
+        )?;
+
+        let command_pool = device_ptr
+            .borrow()
+            .device
+            .create_command_pool_typed(
+                &device_ptr.borrow().queues,
+                CommandPoolCreateFlags::TRANSIENT,
+            )?;
+
+        Ok(OffscreenState {
+            extent,
+            format,
+            image_view: Some(image_view),
+            image: Some(image),
+            memory: Some(memory),
+            readback: Some(readback),
+            command_pool: Some(command_pool),
+            device: device_ptr
+        })
+    }
+
+    /// Copies the last resolved frame into host memory. The caller must
+    /// make sure the submission that rendered it has completed.
+    pub(super) unsafe fn read_pixels(&mut self) -> Result<image::RgbaImage, RenderError> {
+        let image = self.image.as_ref().unwrap();
+        let readback = self.readback.as_ref().unwrap();
+        let command_pool = self.command_pool.as_mut().unwrap();
+        command_pool.reset();
+
+        let mut cmd_buffer = command_pool.acquire_command_buffer::<command::OneShot>();
+        cmd_buffer.begin();
+
+        let image_barrier = Barrier::Image {
+            states: (Access::COLOR_ATTACHMENT_WRITE, Layout::TransferSrcOptimal)
+                ..(Access::TRANSFER_READ, Layout::TransferSrcOptimal),
+            target: image,
+            families: None,
+            range: COLOR_RANGE.clone(),
+        };
+
+        cmd_buffer.pipeline_barrier(
+            PipelineStage::COLOR_ATTACHMENT_OUTPUT..PipelineStage::TRANSFER,
+            MemoryDependencies::empty(),
+            &[image_barrier]
+        );
+
+        cmd_buffer.copy_image_to_buffer(
+            image,
+            Layout::TransferSrcOptimal,
+            readback.get_buffer(),
+            &[command::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_width: readback.row_pitch / ReadbackBuffer::<B>::STRIDE,
+                buffer_height: self.extent.height,
+                image_layers: SubresourceLayers {
+                    aspects: Aspects::COLOR,
+                    level: 0,
+                    layers: 0..1
+                },
+                image_offset: Offset { x: 0, y: 0, z: 0 },
+                image_extent: self.extent
+            }]
+        );
+
+        // makes the copy visible to the host once the queue is idle
+        let buffer_barrier = Barrier::Buffer {
+            states: BufferAccess::TRANSFER_WRITE..BufferAccess::HOST_READ,
+            target: readback.get_buffer(),
+            families: None,
+            range: None..None,
+        };
+
+        cmd_buffer.pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::HOST,
+            MemoryDependencies::empty(),
+            &[buffer_barrier]
+        );
+
+        cmd_buffer.finish();
+
+        {
+            let queue = &mut self.device.borrow_mut().queues.queues[0];
+            queue.submit_without_semaphores(std::iter::once(&cmd_buffer), None);
+            queue.wait_idle()?;
+        }
+
+        readback.read_image()
+    }
+}
+
+impl<B: Backend> Drop for OffscreenState<B> {
+    fn drop(&mut self) {
+        self.readback.take();
+        let mut device = self.device.borrow_mut();
+        unsafe {
+            device.device.destroy_command_pool(
+                self.command_pool.take().unwrap().into_raw());
+            device.device.destroy_image_view(self.image_view.take().unwrap());
+            device.device.destroy_image(self.image.take().unwrap());
+            device.free(self.memory.take().unwrap());
+        }
+    }
+}
@@ -1,151 +1,147 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
-use gfx_hal::{Backend, Device, Primitive};
+use gfx_hal::{Backend, Device, Features};
 use gfx_hal::pso;
+use log::debug;
 use super::device::DeviceState;
-use super::adapter::AdapterState;
-use super::swapchain::SwapchainState;
+use super::error::RenderError;
+use super::pipeline_cache::PipelineCacheState;
+use super::pipeline_desc::{PipelineDesc, PolygonMode, VertexLayout};
 use super::primitives;
+use super::render_pass::RenderPassState;
 
 include!(concat!(env!("OUT_DIR"), "/compiled_shaders.rs"));
 
 const ENTRY_NAME: &str = "main";
 
+/// The per-object model matrix, in 32-bit words.
+pub(super) const PUSH_CONSTANT_RANGE: std::ops::Range<u32> = 0..16;
+
+/// The viewport and scissor are dynamic state, set when recording, so the
+/// pipeline outlives resizes.
 pub(super) struct PipelineState<B: Backend> {
     pub(super) pipeline: Option<B::GraphicsPipeline>,
-    pub(super) pipeline_layout: Option<B::PipelineLayout>,
+    /// What the pipeline was created from.
+    pub(super) desc: PipelineDesc,
     device: Rc<RefCell<DeviceState<B>>>
 }
 
 impl<B: Backend> PipelineState<B> {
-    pub(super) unsafe fn new<IS>(
+    pub(super) unsafe fn new(
         device_ptr: Rc<RefCell<DeviceState<B>>>,
-        adapter: &AdapterState<B>,
-        desc_layouts: IS,
-        render_pass: &B::RenderPass,
-        swapchain: &SwapchainState<B>,
-    ) -> Self
-    where IS: IntoIterator, IS::Item: std::borrow::Borrow<B::DescriptorSetLayout> {
-        let device = &device_ptr.borrow().device;
+        pipeline_layout: &B::PipelineLayout,
+        render_pass: &RenderPassState<B>,
+        cache: &B::PipelineCache,
+        desc: &PipelineDesc,
+    ) -> Result<Self, RenderError> {
+        let vertex_shader = compiled_shader(&desc.shaders.vertex)?;
+        let fragment_shader = match desc.shaders.fragment.as_ref() {
+            Some(name) => Some(compiled_shader(name)?),
+            None => None,
+        };
+        if desc.raster.polygon_mode != PolygonMode::Fill
+            && !device_ptr.borrow().features.contains(Features::NON_FILL_POLYGON_MODE) {
+            return Err(RenderError::Unsupported(format!(
+                "{:?} polygon mode isn't supported by this device", desc.raster.polygon_mode)));
+        }
 
-        let pipeline_layout = device
-            .create_pipeline_layout(desc_layouts, &[])
-            .expect("Can't create pipeline layout");
+        let device = &device_ptr.borrow().device;
 
         let pipeline = {
 
-            let vs_module = device.create_shader_module(
-                &TRIANGLE_VERTEX_SHADER).unwrap();
-            let fs_module = device.create_shader_module(
-                &TRIANGLE_FRAGMENT_SHADER).unwrap();
+            let modules = device
+                .create_shader_module(vertex_shader)
+                .and_then(|vs_module| {
+                    match fragment_shader.map(|fragment_shader| device.create_shader_module(fragment_shader)) {
+                        Some(Ok(fs_module)) => Ok((vs_module, Some(fs_module))),
+                        None => Ok((vs_module, None)),
+                        Some(Err(e)) => {
+                            device.destroy_shader_module(vs_module);
+                            Err(e)
+                        }
+                    }
+                });
+            let (vs_module, fs_module) = modules?;
 
             let pipeline = {
 
-                let (vs_entry, fs_entry) = (
-                    pso::EntryPoint::<B> {
+                let shader_entries = pso::GraphicsShaderSet {
+                    vertex: pso::EntryPoint::<B> {
                         entry: ENTRY_NAME,
                         module: &vs_module,
                         specialization: pso::Specialization::default(),
                     },
-                    pso::EntryPoint::<B> {
-                        entry: ENTRY_NAME,
-                        module: &fs_module,
-                        specialization: pso::Specialization::default(),
-                    },
-                );
-
-                let shader_entries = pso::GraphicsShaderSet {
-                    vertex: vs_entry,
                     hull: None,
                     domain: None,
                     geometry: None,
-                    fragment: Some(fs_entry),
+                    fragment: fs_module.as_ref().map(|fs_module| pso::EntryPoint::<B> {
+                        entry: ENTRY_NAME,
+                        module: fs_module,
+                        specialization: pso::Specialization::default(),
+                    }),
                 };
 
                 let subpass = gfx_hal::pass::Subpass {
                     index: 0,
-                    main_pass: render_pass,
+                    main_pass: render_pass.render_pass.as_ref().unwrap(),
                 };
 
                 let mut pipeline_desc = pso::GraphicsPipelineDesc::new(
                     shader_entries,
-                    Primitive::TriangleList,
-                    pso::Rasterizer {
-                        polygon_mode: pso::PolygonMode::Fill,
-                        cull_face: pso::Face::BACK,
-                        front_face: pso::FrontFace::CounterClockwise,
-                        depth_clamping: false,
-                        depth_bias: None,
-                        conservative: false,
-                    },
-                    &pipeline_layout,
+                    desc.primitive.into(),
+                    desc.raster.into(),
+                    pipeline_layout,
                     subpass,
                 );
 
-                pipeline_desc.baked_states.viewport = Some(pso::Viewport {
-                    rect: pso::Rect {
-                        x: 0,
-                        y: 0,
-                        w: swapchain.extent.width as _,
-                        h: swapchain.extent.height as _,
-                    },
-                    depth: 0.0..1.0
-                });
-
-                pipeline_desc.baked_states.scissor = Some(pso::Rect {
-                    x: 0,
-                    y: 0,
-                    w: swapchain.extent.width as _,
-                    h: swapchain.extent.height as _,
-                });
-
-                pipeline_desc.blender.logic_op = Some(pso::LogicOp::Copy);
                 pipeline_desc.blender.targets.push(pso::ColorBlendDesc(
                     pso::ColorMask::ALL,
-                    pso::BlendState::Off
+                    desc.blend.into()
                 ));
 
-                pipeline_desc.vertex_buffers.push(
-                    primitives::Vertex::BINDING_DESCRIPTION
-                );
+                if desc.vertex_layout == VertexLayout::Model {
+                    pipeline_desc.vertex_buffers.push(
+                        primitives::Vertex::BINDING_DESCRIPTION
+                    );
 
-                pipeline_desc.attributes.extend_from_slice(
-                    &primitives::Vertex::ATTRIBUTE_DESCRIPTIONS
-                );
+                    pipeline_desc.attributes.extend_from_slice(
+                        &primitives::Vertex::ATTRIBUTE_DESCRIPTIONS
+                    );
+                }
 
                 pipeline_desc.depth_stencil = pso::DepthStencilDesc {
-                    depth: pso::DepthTest::On {
-                        fun: pso::Comparison::Less,
-                        write: true
-                    },
+                    depth: desc.depth.into(),
                     depth_bounds: false,
                     stencil: pso::StencilTest::Off
                 };
 
                 pipeline_desc.multisampling = {
                     Some(pso::Multisampling {
-                        rasterization_samples: adapter.get_max_usable_sample_count(),
+                        rasterization_samples: render_pass.samples,
                         sample_shading: None,
                         sample_mask: !0,
-                        alpha_coverage: false,
+                        alpha_coverage: desc.multisampling.alpha_coverage,
                         alpha_to_one: false
                     })
                 };
 
-                device.create_graphics_pipeline(&pipeline_desc, None)
+                device.create_graphics_pipeline(&pipeline_desc, Some(cache))
             };
 
             device.destroy_shader_module(vs_module);
-            device.destroy_shader_module(fs_module);
+            if let Some(fs_module) = fs_module {
+                device.destroy_shader_module(fs_module);
+            }
 
-            pipeline.unwrap()
+            pipeline?
         };
 
-        PipelineState {
+        Ok(PipelineState {
             pipeline: Some(pipeline),
-            pipeline_layout: Some(pipeline_layout),
+            desc: desc.clone(),
             device: Rc::clone(&device_ptr)
-        }
+        })
     }
 }
 
@@ -154,8 +150,99 @@ impl<B: Backend> Drop for PipelineState<B> {
         let device = &self.device.borrow().device;
         unsafe {
             device.destroy_graphics_pipeline(self.pipeline.take().unwrap());
-            device.destroy_pipeline_layout(self.pipeline_layout.take().unwrap());
-            // implicit destroy pipeline on Drop
         }
     }
 }
+
+/// Creates each distinct `PipelineDesc` once and hands out the same
+/// pipeline for equal descriptions. Everything else a pipeline depends on
+/// is the same for all of them and kept here: the layout, the render pass
+/// they draw in and the pipeline cache.
+pub(super) struct PipelineRegistry<B: Backend> {
+    pipelines: HashMap<PipelineDesc, Rc<PipelineState<B>>>,
+    /// Binds the uniform buffer and material descriptor sets and the model
+    /// matrix push constants.
+    pipeline_layout: Option<B::PipelineLayout>,
+    render_pass: Rc<RenderPassState<B>>,
+    cache: PipelineCacheState<B>,
+    device: Rc<RefCell<DeviceState<B>>>,
+}
+
+impl<B: Backend> PipelineRegistry<B> {
+    pub(super) unsafe fn new<IS>(
+        device: Rc<RefCell<DeviceState<B>>>,
+        desc_layouts: IS,
+        render_pass: Rc<RenderPassState<B>>,
+        cache: PipelineCacheState<B>
+    ) -> Result<Self, RenderError>
+    where IS: IntoIterator, IS::Item: std::borrow::Borrow<B::DescriptorSetLayout> {
+        let pipeline_layout = device
+            .borrow()
+            .device
+            .create_pipeline_layout(
+                desc_layouts,
+                &[(pso::ShaderStageFlags::VERTEX, PUSH_CONSTANT_RANGE)]
+            )?;
+
+        Ok(PipelineRegistry {
+            pipelines: HashMap::new(),
+            pipeline_layout: Some(pipeline_layout),
+            render_pass,
+            cache,
+            device
+        })
+    }
+
+    pub(super) fn get_layout(&self) -> &B::PipelineLayout {
+        self.pipeline_layout.as_ref().unwrap()
+    }
+
+    /// The pipeline for `desc`, created unless an equal description was
+    /// asked for since the render pass was last replaced.
+    pub(super) unsafe fn get(&mut self, desc: &PipelineDesc) -> Result<Rc<PipelineState<B>>, RenderError> {
+        if let Some(pipeline) = self.pipelines.get(desc) {
+            return Ok(Rc::clone(pipeline));
+        }
+
+        debug!("Creating pipeline {:?}", desc);
+        let pipeline = Rc::new(PipelineState::new(
+            Rc::clone(&self.device),
+            self.pipeline_layout.as_ref().unwrap(),
+            &self.render_pass,
+            self.cache.get_cache(),
+            desc
+        )?);
+        self.pipelines.insert(desc.clone(), Rc::clone(&pipeline));
+        Ok(pipeline)
+    }
+
+    /// Pipelines are tied to a compatible render pass, so all of them are
+    /// forgotten and created again for `render_pass` when next asked for.
+    /// Pipelines still held elsewhere live on until dropped.
+    pub(super) fn set_render_pass(&mut self, render_pass: Rc<RenderPassState<B>>) {
+        if !Rc::ptr_eq(&self.render_pass, &render_pass) {
+            self.pipelines.clear();
+            self.render_pass = render_pass;
+        }
+    }
+}
+
+impl<B: Backend> Drop for PipelineRegistry<B> {
+    fn drop(&mut self) {
+        self.pipelines.clear();
+        unsafe {
+            self.device
+                .borrow()
+                .device
+                .destroy_pipeline_layout(self.pipeline_layout.take().unwrap());
+        }
+    }
+}
+
+fn compiled_shader(name: &str) -> Result<&'static [u8], RenderError> {
+    COMPILED_SHADERS
+        .iter()
+        .find(|(file_name, _)| *file_name == name)
+        .map(|(_, spirv)| *spirv)
+        .ok_or_else(|| RenderError::Shader(format!("no compiled shader named {}", name)))
+}
@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+use gfx_hal::{Backend, Device};
+use gfx_hal::adapter::AdapterInfo;
+use log::{debug, warn};
+
+use super::device::DeviceState;
+use super::error::RenderError;
+
+use crate::consts::APP_TITLE;
+
+const FILE_NAME: &str = "pipeline_cache.bin";
+const MAGIC: &[u8; 4] = b"PLCH";
+/// Bumped whenever the header layout changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// Caches of different backends can't be mixed, even on one adapter.
+#[cfg(feature = "dx11")]
+const BACKEND_NAME: &str = "dx11";
+#[cfg(feature = "dx12")]
+const BACKEND_NAME: &str = "dx12";
+#[cfg(feature = "gl")]
+const BACKEND_NAME: &str = "gl";
+#[cfg(feature = "metal")]
+const BACKEND_NAME: &str = "metal";
+#[cfg(feature = "vulkan")]
+const BACKEND_NAME: &str = "vulkan";
+
+/// A pipeline cache loaded from the user's cache directory and written
+/// back when dropped, so pipelines compile faster on the next run.
+///
+/// The file starts with a header naming the backend and adapter it was
+/// created on, and is discarded when either differs. Drivers also check
+/// their own header in the data, which covers driver updates.
+pub(super) struct PipelineCacheState<B: Backend> {
+    cache: Option<B::PipelineCache>,
+    /// Where the cache is written to, `None` if it isn't kept.
+    path: Option<PathBuf>,
+    header: Vec<u8>,
+    device: Rc<RefCell<DeviceState<B>>>,
+}
+
+impl<B: Backend> PipelineCacheState<B> {
+    /// Starts empty when `persistent` is off or no cache directory is
+    /// known. A missing, unreadable or stale file isn't an error either.
+    pub(super) unsafe fn new(
+        device_ptr: Rc<RefCell<DeviceState<B>>>,
+        info: &AdapterInfo,
+        persistent: bool
+    ) -> Result<Self, RenderError> {
+        let header = header(BACKEND_NAME, info);
+        let path = if persistent { cache_path() } else { None };
+
+        let data = path.as_ref().and_then(|path| match fs::read(path) {
+            Ok(contents) => {
+                if contents.starts_with(&header) {
+                    debug!("Loaded pipeline cache from {}", path.display());
+                    Some(contents[header.len()..].to_vec())
+                } else {
+                    warn!("Discarding pipeline cache {} made for another adapter or version",
+                          path.display());
+                    None
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Can't read pipeline cache {}: {}", path.display(), e);
+                None
+            }
+        });
+
+        let cache = {
+            let device = &device_ptr.borrow().device;
+            match data {
+                Some(data) => match device.create_pipeline_cache(Some(&data)) {
+                    Ok(cache) => cache,
+                    // the driver may still reject data it doesn't recognize
+                    Err(_) => device.create_pipeline_cache(None)?,
+                },
+                None => device.create_pipeline_cache(None)?,
+            }
+        };
+
+        Ok(PipelineCacheState {
+            cache: Some(cache),
+            path,
+            header,
+            device: device_ptr
+        })
+    }
+
+    pub(super) fn get_cache(&self) -> &B::PipelineCache {
+        self.cache.as_ref().unwrap()
+    }
+
+    /// Writes the cache to disk, through a temporary file so an
+    /// interrupted write never leaves a truncated cache behind.
+    pub(super) fn save(&self) -> Result<(), RenderError> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let data = unsafe {
+            self.device.borrow().device.get_pipeline_cache_data(self.get_cache())?
+        };
+        let mut contents = self.header.clone();
+        contents.extend_from_slice(&data);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, &contents)?;
+        fs::rename(&temp_path, path)?;
+        debug!("Saved {} byte pipeline cache to {}", data.len(), path.display());
+        Ok(())
+    }
+}
+
+impl<B: Backend> Drop for PipelineCacheState<B> {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            warn!("Can't save pipeline cache: {}", e);
+        }
+        unsafe {
+            self.device.borrow().device.destroy_pipeline_cache(self.cache.take().unwrap());
+        }
+    }
+}
+
+/// Identifies the backend and adapter the cache data belongs to.
+fn header(backend: &str, info: &AdapterInfo) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.extend_from_slice(&(info.vendor as u64).to_le_bytes());
+    header.extend_from_slice(&(info.device as u64).to_le_bytes());
+    for name in &[backend, info.name.as_str()] {
+        header.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+    }
+    header
+}
+
+/// The file in the platform's per-user cache directory.
+fn cache_path() -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join(APP_TITLE.to_lowercase()).join(FILE_NAME))
+}
+
+#[cfg(target_os = "windows")]
+fn cache_dir() -> Option<PathBuf> {
+    env_path("LOCALAPPDATA")
+}
+
+#[cfg(target_os = "macos")]
+fn cache_dir() -> Option<PathBuf> {
+    env_path("HOME").map(|home| home.join("Library").join("Caches"))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn cache_dir() -> Option<PathBuf> {
+    env_path("XDG_CACHE_HOME").or_else(|| env_path("HOME").map(|home| home.join(".cache")))
+}
+
+/// Only absolute paths count, as relative ones would depend on the
+/// working directory.
+fn env_path(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+}
+
+#[cfg(test)]
+mod tests {
+    use gfx_hal::adapter::{AdapterInfo, DeviceType};
+
+    use super::header;
+
+    fn info() -> AdapterInfo {
+        AdapterInfo {
+            name: "Test GPU".to_string(),
+            vendor: 0x10de,
+            device: 0x1b80,
+            device_type: DeviceType::DiscreteGpu,
+        }
+    }
+
+    #[test]
+    fn header_is_stable() {
+        assert_eq!(header("vulkan", &info()), header("vulkan", &info()));
+    }
+
+    #[test]
+    fn header_changes_with_backend_and_adapter() {
+        let original = header("vulkan", &info());
+
+        assert_ne!(header("dx12", &info()), original);
+
+        let mut vendor = info();
+        vendor.vendor = 0x1002;
+        assert_ne!(header("vulkan", &vendor), original);
+
+        let mut device = info();
+        device.device = 0x1b81;
+        assert_ne!(header("vulkan", &device), original);
+
+        let mut name = info();
+        name.name = "Test GPU 2".to_string();
+        assert_ne!(header("vulkan", &name), original);
+    }
+
+    #[test]
+    fn header_is_not_a_prefix_of_another() {
+        // a cache is used when the file starts with the header, so names
+        // running into the data must not match
+        let mut longer = info();
+        longer.name = "Test GPU Ti".to_string();
+        assert!(!header("vulkan", &longer).starts_with(&header("vulkan", &info())));
+    }
+}
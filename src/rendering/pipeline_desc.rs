@@ -0,0 +1,243 @@
+use gfx_hal::{pso, Primitive};
+use serde::{Deserialize, Serialize};
+
+/// Everything that sets one graphics pipeline apart from another, in a
+/// form that can be read from asset files. Descriptions are compared and
+/// hashed to share pipelines, see `PipelineRegistry`.
+///
+/// The pipeline layout and sample count aren't part of it, as they are
+/// the same for every pipeline drawn into the scene's render pass.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineDesc {
+    pub primitive: Topology,
+    pub vertex_layout: VertexLayout,
+    pub blend: BlendMode,
+    pub shaders: ShaderSet,
+    pub raster: RasterDesc,
+    pub depth: DepthDesc,
+    pub multisampling: MultisampleDesc,
+}
+
+/// Shaders by file name in `shaders/`, compiled into the binary.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShaderSet {
+    pub vertex: String,
+    /// Without one only depth is written.
+    pub fragment: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topology {
+    PointList,
+    LineList,
+    LineStrip,
+    TriangleList,
+    TriangleStrip,
+}
+
+/// Where vertex attributes come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VertexLayout {
+    /// No vertex buffer, the vertex shader makes up its vertices, as for
+    /// fullscreen passes.
+    Empty,
+    /// Model vertices with position, color, texture coordinate and normal.
+    Model,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RasterDesc {
+    pub polygon_mode: PolygonMode,
+    pub cull_face: CullFace,
+    pub front_face: FrontFace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolygonMode {
+    Fill,
+    /// Wireframe, where the device supports it.
+    Line,
+    Point,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CullFace {
+    None,
+    Front,
+    Back,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrontFace {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DepthDesc {
+    /// Fragments pass when this holds against the stored depth. `Always`
+    /// without `write` turns depth testing off.
+    pub test: Comparison,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Never,
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+/// How the color target is blended with what is already there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    /// Overwrites the target.
+    Off,
+    /// Straight alpha, for transparent materials.
+    Alpha,
+    PremultipliedAlpha,
+    Additive,
+}
+
+/// The sample count is the render pass's, only how samples are used can
+/// differ between pipelines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultisampleDesc {
+    /// Turns fragment alpha into a sample mask, for cutout materials
+    /// that don't need sorting.
+    pub alpha_coverage: bool,
+}
+
+/// Opaque, textured models, as drawn by the main scene.
+impl Default for PipelineDesc {
+    fn default() -> Self {
+        PipelineDesc {
+            primitive: Topology::TriangleList,
+            vertex_layout: VertexLayout::Model,
+            blend: BlendMode::Off,
+            shaders: ShaderSet::default(),
+            raster: RasterDesc::default(),
+            depth: DepthDesc::default(),
+            multisampling: MultisampleDesc::default(),
+        }
+    }
+}
+
+impl Default for ShaderSet {
+    fn default() -> Self {
+        ShaderSet {
+            vertex: "triangle.vert".to_string(),
+            fragment: Some("triangle.frag".to_string()),
+        }
+    }
+}
+
+impl Default for RasterDesc {
+    fn default() -> Self {
+        RasterDesc {
+            polygon_mode: PolygonMode::Fill,
+            cull_face: CullFace::Back,
+            front_face: FrontFace::CounterClockwise,
+        }
+    }
+}
+
+impl Default for DepthDesc {
+    fn default() -> Self {
+        DepthDesc {
+            test: Comparison::Less,
+            write: true,
+        }
+    }
+}
+
+impl From<Topology> for Primitive {
+    fn from(topology: Topology) -> Self {
+        match topology {
+            Topology::PointList => Primitive::PointList,
+            Topology::LineList => Primitive::LineList,
+            Topology::LineStrip => Primitive::LineStrip,
+            Topology::TriangleList => Primitive::TriangleList,
+            Topology::TriangleStrip => Primitive::TriangleStrip,
+        }
+    }
+}
+
+impl From<RasterDesc> for pso::Rasterizer {
+    fn from(raster: RasterDesc) -> Self {
+        pso::Rasterizer {
+            polygon_mode: match raster.polygon_mode {
+                PolygonMode::Fill => pso::PolygonMode::Fill,
+                PolygonMode::Line => pso::PolygonMode::Line(pso::State::Static(1.0)),
+                PolygonMode::Point => pso::PolygonMode::Point,
+            },
+            cull_face: match raster.cull_face {
+                CullFace::None => pso::Face::NONE,
+                CullFace::Front => pso::Face::FRONT,
+                CullFace::Back => pso::Face::BACK,
+            },
+            front_face: match raster.front_face {
+                FrontFace::Clockwise => pso::FrontFace::Clockwise,
+                FrontFace::CounterClockwise => pso::FrontFace::CounterClockwise,
+            },
+            depth_clamping: false,
+            depth_bias: None,
+            conservative: false,
+        }
+    }
+}
+
+impl From<DepthDesc> for pso::DepthTest {
+    fn from(depth: DepthDesc) -> Self {
+        match depth {
+            DepthDesc { test: Comparison::Always, write: false } => pso::DepthTest::Off,
+            DepthDesc { test, write } => pso::DepthTest::On {
+                fun: test.into(),
+                write
+            },
+        }
+    }
+}
+
+impl From<Comparison> for pso::Comparison {
+    fn from(comparison: Comparison) -> Self {
+        match comparison {
+            Comparison::Never => pso::Comparison::Never,
+            Comparison::Less => pso::Comparison::Less,
+            Comparison::Equal => pso::Comparison::Equal,
+            Comparison::LessEqual => pso::Comparison::LessEqual,
+            Comparison::Greater => pso::Comparison::Greater,
+            Comparison::NotEqual => pso::Comparison::NotEqual,
+            Comparison::GreaterEqual => pso::Comparison::GreaterEqual,
+            Comparison::Always => pso::Comparison::Always,
+        }
+    }
+}
+
+impl From<BlendMode> for pso::BlendState {
+    fn from(blend: BlendMode) -> Self {
+        match blend {
+            BlendMode::Off => pso::BlendState::Off,
+            BlendMode::Alpha => pso::BlendState::ALPHA,
+            BlendMode::PremultipliedAlpha => pso::BlendState::PREMULTIPLIED_ALPHA,
+            BlendMode::Additive => pso::BlendState::ADD,
+        }
+    }
+}
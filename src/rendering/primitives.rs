@@ -1,6 +1,7 @@
 extern crate tobj;
 
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::hash::{Hash, Hasher};
 use std::time::Instant;
 use std::collections::HashMap;
@@ -8,15 +9,18 @@ use gfx_hal::pso;
 use gfx_hal::format as f;
 
 use glm::{Mat4,Vec2,Vec3,vec3,vec2};
+use super::pipeline_desc::PipelineDesc;
 use super::utils::hash_float;
 
 use log::debug;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
 pub struct Vertex {
     pub pos: Vec3,
     pub color: Vec3,
-    pub tex_coord: Vec2
+    pub tex_coord: Vec2,
+    pub normal: Vec3
 }
 
 impl Hash for Vertex {
@@ -24,6 +28,7 @@ impl Hash for Vertex {
         (0..3).for_each(|i| hash_float(self.pos[i], state));
         (0..3).for_each(|i| hash_float(self.color[i], state));
         (0..2).for_each(|i| hash_float(self.tex_coord[i], state));
+        (0..3).for_each(|i| hash_float(self.normal[i], state));
     }
 }
 
@@ -37,7 +42,7 @@ impl Vertex {
         rate: pso::VertexInputRate::Vertex,
     };
 
-    pub const ATTRIBUTE_DESCRIPTIONS: [pso::AttributeDesc; 3] = [
+    pub const ATTRIBUTE_DESCRIPTIONS: [pso::AttributeDesc; 4] = [
         pso::AttributeDesc {
             location: 0,
             binding: 0,
@@ -61,16 +66,26 @@ impl Vertex {
                 format: f::Format::Rg32Sfloat,
                 offset: (std::mem::size_of::<Vec3>() * 2) as _
             },
+        },
+        pso::AttributeDesc {
+            location: 3,
+            binding: 0,
+            element: pso::Element {
+                format: f::Format::Rgb32Sfloat,
+                offset: (std::mem::size_of::<Vec3>() * 2 + std::mem::size_of::<Vec2>()) as _
+            },
         }
     ];
 }
 
 macro_rules! vert {
-    ( $x:expr, $y:expr, $z: expr, $r:expr, $g:expr, $b:expr, $tx:expr, $ty:expr ) => {
+    ( $x:expr, $y:expr, $z: expr, $r:expr, $g:expr, $b:expr, $tx:expr, $ty:expr,
+      $nx:expr, $ny:expr, $nz:expr ) => {
         Vertex {
             pos: vec3($x, $y, $z),
             color: vec3($r, $g, $b),
-            tex_coord: vec2($tx, $ty)
+            tex_coord: vec2($tx, $ty),
+            normal: vec3($nx, $ny, $nz)
         }
     };
 }
@@ -82,34 +97,94 @@ pub struct UniformBufferObject {
     pub proj: Mat4
 }
 
+/// A part of a model drawn with a single material.
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub indicies: Range<u32>,
+    pub material: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TextureSource {
+    File(PathBuf),
+    /// Index into `Model::images`.
+    Embedded(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub name: String,
+    pub diffuse: Vec3,
+    pub diffuse_texture: Option<TextureSource>,
+    /// How meshes using the material are drawn.
+    pub pipeline: PipelineDesc,
+}
+
 pub struct Model {
     pub vertices: Vec<Vertex>,
-    pub indicies: Vec<u32>
+    pub indicies: Vec<u32>,
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<Material>,
+    /// Textures that came with the model file rather than as a path.
+    pub images: Vec<image::RgbaImage>
 }
 
 impl Model {
-    pub fn load(file: &Path) -> Self {
+    pub fn load(file: &Path) -> Result<Self, tobj::LoadError> {
         debug!("Reading model file: {:?}", file);
         let start_time = Instant::now();
-        let (models, _materials) = tobj::load_obj(file).unwrap();
+        let (models, obj_materials) = tobj::load_obj(file)?;
         debug!("Reading took {}s", start_time.elapsed().as_millis() as f64 / 1000.0);
-        debug!("Processing {} models", models.len());
+        debug!("Processing {} models, {} materials", models.len(), obj_materials.len());
+
+        // texture paths in .mtl files are relative to the model
+        let base_dir = file.parent().unwrap_or(Path::new(""));
+        let materials: Vec<Material> = obj_materials
+            .into_iter()
+            .map(|material| Material {
+                name: material.name,
+                diffuse: vec3(material.diffuse[0], material.diffuse[1], material.diffuse[2]),
+                diffuse_texture: if material.diffuse_texture.is_empty() {
+                    None
+                } else {
+                    Some(TextureSource::File(base_dir.join(&material.diffuse_texture)))
+                },
+                pipeline: PipelineDesc::default(),
+            })
+            .collect();
+
         let mut vertices = Vec::new();
         let mut indicies = Vec::new();
+        let mut meshes = Vec::new();
         let mut unique_vertices: HashMap<Vertex, u32> = HashMap::new();
 
         for model in models {
             let mesh = &model.mesh;
             debug!("Processing {} indicies", &mesh.indices.len());
+            let color = mesh.material_id
+                .map(|id| materials[id].diffuse)
+                .unwrap_or(vec3(1.0, 1.0, 1.0));
+            let first_index = indicies.len() as u32;
             for index in &mesh.indices {
                 let i = *index as usize;
+                let (u, v) = if mesh.texcoords.is_empty() {
+                    (0.0, 0.0)
+                } else {
+                    (mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1])
+                };
+                let (nx, ny, nz) = if mesh.normals.is_empty() {
+                    (0.0, 0.0, 0.0)
+                } else {
+                    (mesh.normals[i * 3], mesh.normals[i * 3 + 1], mesh.normals[i * 3 + 2])
+                };
                 let vertex = vert!(
                     mesh.positions[i * 3],
                     mesh.positions[i * 3 + 1],
                     mesh.positions[i * 3 + 2],
-                    1.0, 1.0, 1.0,
-                    mesh.texcoords[i * 2],
-                    1.0 - mesh.texcoords[i * 2 + 1]
+                    color[0], color[1], color[2],
+                    u,
+                    1.0 - v,
+                    nx, ny, nz
                 );
                 let index = match unique_vertices.get(&vertex) {
                     Some(&idx) => idx,
@@ -122,11 +197,18 @@ impl Model {
                 };
                 indicies.push(index);
             }
+            meshes.push(Mesh {
+                indicies: first_index..indicies.len() as u32,
+                material: mesh.material_id
+            });
         }
         debug!("Done loading model: {:?}. Took: {}s", file, start_time.elapsed().as_millis() as f64 / 1000.0);
-        Model {
+        Ok(Model {
             vertices,
-            indicies
-        }
+            indicies,
+            meshes,
+            materials,
+            images: vec![]
+        })
     }
 }
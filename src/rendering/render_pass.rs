@@ -1,16 +1,22 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 use gfx_hal::{Backend, Device};
+use gfx_hal::format::Format;
 use gfx_hal::image;
 use gfx_hal::image::Layout;
 use gfx_hal::pass;
 use gfx_hal::pso;
 use super::device::DeviceState;
 use super::adapter::AdapterState;
-use super::swapchain::SwapchainState;
+use super::error::RenderError;
 
 pub(super) struct RenderPassState<B: Backend> {
     pub(super) render_pass: Option<B::RenderPass>,
+    /// Color attachment format the pass was created for.
+    pub(super) format: Format,
+    /// Sample count of the color and depth attachments, which pipelines
+    /// drawn in the pass have to match.
+    pub(super) samples: u8,
     device: Rc<RefCell<DeviceState<B>>>,
 }
 
@@ -18,14 +24,15 @@ impl<B: Backend> RenderPassState<B> {
     pub(super) unsafe fn new(
         device: Rc<RefCell<DeviceState<B>>>,
         adapter: &AdapterState<B>,
-        swapchain: &SwapchainState<B>
-    ) -> Self {
-        let render_pass = {
+        format: Format,
+        resolve_layout: Layout
+    ) -> Result<Self, RenderError> {
+        let samples = adapter.get_max_usable_sample_count();
 
-            let samples = adapter.get_max_usable_sample_count();
+        let render_pass = {
 
             let color_attachment = pass::Attachment {
-                format: Some(swapchain.format.clone()),
+                format: Some(format),
                 samples: samples,
                 ops: pass::AttachmentOps::new(
                     pass::AttachmentLoadOp::Clear,
@@ -36,7 +43,7 @@ impl<B: Backend> RenderPassState<B> {
             };
 
             let depth_attachment = pass::Attachment {
-                format: device.borrow().optimal_depth_format(),
+                format: adapter.optimal_depth_format(),
                 samples: samples,
                 ops: pass::AttachmentOps::new(
                     pass::AttachmentLoadOp::Clear,
@@ -47,14 +54,14 @@ impl<B: Backend> RenderPassState<B> {
             };
 
             let color_attachment_resolve = pass::Attachment {
-                format: Some(swapchain.format.clone()),
+                format: Some(format),
                 samples: 1,
                 ops: pass::AttachmentOps::new(
                     pass::AttachmentLoadOp::DontCare,
                     pass::AttachmentStoreOp::Store,
                 ),
                 stencil_ops: pass::AttachmentOps::DONT_CARE,
-                layouts: Layout::Undefined..Layout::Present
+                layouts: Layout::Undefined..resolve_layout
             };
 
             let subpass = pass::SubpassDesc {
@@ -82,14 +89,15 @@ impl<B: Backend> RenderPassState<B> {
                                       depth_attachment,
                                       color_attachment_resolve],
                                     &[subpass],
-                                    &[dependency])
-                .ok()
+                                    &[dependency])?
         };
 
-        RenderPassState {
-            render_pass,
+        Ok(RenderPassState {
+            render_pass: Some(render_pass),
+            format,
+            samples,
             device
-        }
+        })
     }
 }
 
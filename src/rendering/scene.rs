@@ -0,0 +1,62 @@
+use glm::Mat4;
+
+use super::assets::Assets;
+
+/// An instance of one of the scene's models placed in the world.
+#[derive(Debug, Clone)]
+pub struct SceneObject {
+    model: usize,
+    pub transform: Mat4,
+}
+
+impl SceneObject {
+    /// Index into the models the scene's `Assets` were loaded with.
+    pub fn model(&self) -> usize {
+        self.model
+    }
+}
+
+/// Objects are only added through `add_object`, so every one of them
+/// refers to a loaded model.
+pub struct Scene {
+    pub(super) assets: Assets,
+    objects: Vec<SceneObject>,
+}
+
+impl Scene {
+    /// A scene with one untransformed object per loaded model.
+    pub fn new(assets: Assets) -> Self {
+        let objects = (0..assets.models.len())
+            .map(|model| SceneObject {
+                model,
+                transform: Mat4::identity()
+            })
+            .collect();
+
+        Scene {
+            assets,
+            objects
+        }
+    }
+
+    pub fn model_count(&self) -> usize {
+        self.assets.models.len()
+    }
+
+    pub fn objects(&self) -> &[SceneObject] {
+        &self.objects
+    }
+
+    /// Objects can be moved but not pointed at another model.
+    pub fn objects_mut(&mut self) -> &mut [SceneObject] {
+        &mut self.objects
+    }
+
+    pub fn add_object(&mut self, model: usize, transform: Mat4) {
+        assert!(model < self.model_count(), "no model with index {}", model);
+        self.objects.push(SceneObject {
+            model,
+            transform
+        });
+    }
+}
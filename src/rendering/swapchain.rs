@@ -1,13 +1,16 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 use log::debug;
-use gfx_hal::{Backend, Device, Surface, SwapchainConfig};
+use gfx_hal::{Backend, Device, PresentMode, Surface, SwapchainConfig};
 use gfx_hal::image::Extent;
 use gfx_hal::format::{ChannelType, Format};
 use gfx_hal::window::Extent2D;
 use super::device::DeviceState;
+use super::error::RenderError;
 use super::BackendState;
 
+use crate::config::{self, RenderingConfig};
+
 use crate::window::{DEFAULT_WIDTH, DEFAULT_HEIGHT};
 
 const DEFAULT_EXTENT: Extent2D = Extent2D {
@@ -20,43 +23,107 @@ pub(super) struct SwapchainState<B: Backend> {
     pub(super) backbuffer: Option<Vec<B::Image>>,
     pub(super) extent: Extent,
     pub(super) format: Format,
+    pub(super) present_mode: PresentMode,
+    /// Everything the surface supports, for switching at runtime.
+    present_modes: Vec<PresentMode>,
     device: Rc<RefCell<DeviceState<B>>>,
 }
 
 impl<B: Backend> SwapchainState<B> {
-    pub(super) unsafe fn new(backend: &mut BackendState<B>, device: Rc<RefCell<DeviceState<B>>>) -> Self {
-        let (caps, formats, _present_modes) = backend
+    pub(super) unsafe fn new(
+        backend: &mut BackendState<B>,
+        device: Rc<RefCell<DeviceState<B>>>,
+        config: &RenderingConfig
+    ) -> Result<Self, RenderError> {
+        let (caps, formats, present_modes) = backend
             .surface
-            .compatibility(&device.borrow().physical_device);
+            .as_ref()
+            .unwrap()
+            .compatibility(&backend.adapter.adapter.physical_device);
         debug!("formats: {:?}", formats);
-        let format = formats.map_or(Format::Bgra8Unorm, |formats| {
+        let channel_type = if config.srgb { ChannelType::Srgb } else { ChannelType::Unorm };
+        let default_format = if config.srgb { Format::Bgra8Srgb } else { Format::Bgra8Unorm };
+        let format = formats.map_or(default_format, |formats| {
             formats
                 .iter()
-                .find(|format| format.base_format().1 == ChannelType::Unorm)
-                .map(|format| *format)
+                .find(|format| format.base_format().1 == channel_type)
+                .cloned()
                 .unwrap_or(formats[0])
         });
         debug!("Surface format: {:?}", format);
-        let swap_config = SwapchainConfig::from_caps(&caps, format, DEFAULT_EXTENT);
+        let window_extent = backend.window_extent().unwrap_or(DEFAULT_EXTENT);
+        // surfaces without a fixed size take whatever we ask for, within limits
+        let window_extent = Extent2D {
+            width: window_extent.width
+                .max(caps.extents.start.width)
+                .min(caps.extents.end.width),
+            height: window_extent.height
+                .max(caps.extents.start.height)
+                .min(caps.extents.end.height)
+        };
+        debug!("present modes: {:?}", present_modes);
+        let present_mode = choose_present_mode(config.present_mode, &present_modes);
+        debug!("Present mode: {:?}", present_mode);
+        let mut swap_config = SwapchainConfig::from_caps(&caps, format, window_extent)
+            .with_mode(present_mode);
+        if let Some(image_count) = config.swapchain_images {
+            swap_config = swap_config.with_image_count(image_count
+                .max(caps.image_count.start)
+                .min(caps.image_count.end));
+        }
         debug!("Swapchain Config: {:?}", swap_config);
         let extent = swap_config.extent.to_extent();
         debug!("Extent: {:?}", extent);
         let (swapchain, backbuffer) = device
             .borrow()
             .device
-            .create_swapchain(&mut backend.surface, swap_config, None)
-            .expect("Can't create swapchain");
+            .create_swapchain(backend.surface.as_mut().unwrap(), swap_config, None)?;
 
-        SwapchainState {
+        Ok(SwapchainState {
             swapchain: Some(swapchain),
             backbuffer: Some(backbuffer),
             device,
             extent,
-            format
-        }
+            format,
+            present_mode,
+            present_modes
+        })
+    }
+
+    /// The supported mode after the current one, in `config::PresentMode::ALL` order.
+    pub(super) fn next_present_mode(&self) -> config::PresentMode {
+        let all = &config::PresentMode::ALL;
+        let current = all
+            .iter()
+            .position(|&mode| hal_present_mode(mode) == self.present_mode)
+            .unwrap_or(0);
+        (1..=all.len())
+            .map(|offset| all[(current + offset) % all.len()])
+            .find(|&mode| self.present_modes.contains(&hal_present_mode(mode)))
+            .unwrap_or(all[current])
     }
 }
 
+fn hal_present_mode(mode: config::PresentMode) -> PresentMode {
+    match mode {
+        config::PresentMode::Fifo => PresentMode::Fifo,
+        config::PresentMode::Mailbox => PresentMode::Mailbox,
+        config::PresentMode::Immediate => PresentMode::Immediate,
+        config::PresentMode::Relaxed => PresentMode::Relaxed,
+    }
+}
+
+fn choose_present_mode(preferred: config::PresentMode, supported: &[PresentMode]) -> PresentMode {
+    preferred
+        .fallbacks()
+        .iter()
+        .map(|&mode| hal_present_mode(mode))
+        .find(|mode| supported.contains(mode))
+        // FIFO support is required, but not every backend reports it
+        .or_else(|| supported.first().cloned())
+        .unwrap_or(PresentMode::Fifo)
+}
+
 impl<B: Backend> Drop for SwapchainState<B> {
     fn drop(&mut self) {
         debug!("~~DROP SwapchainState");
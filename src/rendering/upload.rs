@@ -0,0 +1,536 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::rc::Rc;
+use gfx_hal::{Backend, CommandPool, Device, Graphics, Transfer};
+use gfx_hal::buffer::{Access as BufferAccess, Usage};
+use gfx_hal::command::{self, CommandBuffer, OneShot};
+use gfx_hal::format::Aspects;
+use gfx_hal::image::{Access as ImageAccess, Extent, Layout, Offset,
+                     SubresourceLayers, SubresourceRange};
+use gfx_hal::memory::{Barrier, Dependencies, Properties};
+use gfx_hal::pool::CommandPoolCreateFlags;
+use gfx_hal::pso::PipelineStage;
+use gfx_hal::queue::{QueueFamilyId, Submission};
+use image;
+use log::debug;
+
+use super::adapter::AdapterState;
+use super::allocator::align_up;
+use super::buffer::BufferState;
+use super::device::DeviceState;
+use super::error::RenderError;
+
+/// Size of the persistently mapped staging buffer. Single copies that
+/// don't fit get a staging buffer of their own.
+const STAGING_RING_SIZE: u64 = 16 * 1024 * 1024;
+/// Bytes per texel of uploaded images.
+const TEXEL_SIZE: u32 = 4;
+
+/// Commands being recorded, not yet submitted.
+struct Recording<B: Backend> {
+    commands: CommandBuffer<B, Graphics, OneShot>,
+    /// Copies go here instead of `commands` when there is a transfer
+    /// queue. Submitted first, `commands` waits for it.
+    transfers: Option<CommandBuffer<B, Transfer, OneShot>>,
+    /// Bytes of the ring taken, including alignment and wrap-around
+    /// padding.
+    ring_bytes: u64,
+    oversized: Vec<BufferState<B>>,
+}
+
+/// Submitted commands, whose staging memory is reused once `fence` is
+/// signalled.
+struct Batch<B: Backend> {
+    // kept until the commands are done, the pools are reset once none
+    // are left
+    _commands: CommandBuffer<B, Graphics, OneShot>,
+    _transfers: Option<CommandBuffer<B, Transfer, OneShot>>,
+    fence: B::Fence,
+    /// Signalled by the transfer queue for the graphics queue.
+    semaphore: Option<B::Semaphore>,
+    ring_bytes: u64,
+    _oversized: Vec<BufferState<B>>,
+}
+
+/// The command buffer copies are recorded into.
+enum CopyCommands<'a, B: Backend> {
+    Transfer(&'a mut CommandBuffer<B, Transfer, OneShot>),
+    Graphics(&'a mut CommandBuffer<B, Graphics, OneShot>),
+}
+
+impl<'a, B: Backend> CopyCommands<'a, B> {
+    unsafe fn pipeline_barrier(&mut self, stages: Range<PipelineStage>, barriers: &[Barrier<B>]) {
+        match self {
+            CopyCommands::Transfer(commands) =>
+                commands.pipeline_barrier(stages, Dependencies::empty(), barriers),
+            CopyCommands::Graphics(commands) =>
+                commands.pipeline_barrier(stages, Dependencies::empty(), barriers),
+        }
+    }
+
+    unsafe fn copy_buffer(&mut self, src: &B::Buffer, dst: &B::Buffer, region: command::BufferCopy) {
+        match self {
+            CopyCommands::Transfer(commands) => commands.copy_buffer(src, dst, &[region]),
+            CopyCommands::Graphics(commands) => commands.copy_buffer(src, dst, &[region]),
+        }
+    }
+
+    unsafe fn copy_buffer_to_image(
+        &mut self,
+        src: &B::Buffer,
+        dst: &B::Image,
+        region: command::BufferImageCopy
+    ) {
+        match self {
+            CopyCommands::Transfer(commands) =>
+                commands.copy_buffer_to_image(src, dst, Layout::TransferDstOptimal, &[region]),
+            CopyCommands::Graphics(commands) =>
+                commands.copy_buffer_to_image(src, dst, Layout::TransferDstOptimal, &[region]),
+        }
+    }
+}
+
+/// Uploads data into device local buffers and images. The data is
+/// written into a staging ring that stays mapped, and the copies are
+/// recorded into one command buffer that `flush` submits with a fence,
+/// so many resources are uploaded by a single submission.
+///
+/// If the device has a transfer queue the copies run there, and the
+/// destinations are handed over to the graphics queue afterwards.
+///
+/// Destinations must live until the copies are done, see `wait`.
+pub(super) struct UploadManager<B: Backend> {
+    ring: BufferState<B>,
+    mapping: *mut u8,
+    /// Where the next data is written.
+    head: u64,
+    /// Bytes in use by recorded and unfinished copies, ending at `head`.
+    used: u64,
+    offset_alignment: u64,
+    pitch_alignment: u32,
+    recording: Option<Recording<B>>,
+    /// Oldest first, the ring is freed in that order.
+    in_flight: VecDeque<Batch<B>>,
+    command_pool: Option<CommandPool<B, Graphics>>,
+    transfer_pool: Option<CommandPool<B, Transfer>>,
+    /// From the transfer to the graphics queue family, if they differ.
+    ownership_transfer: Option<Range<QueueFamilyId>>,
+    device: Rc<RefCell<DeviceState<B>>>,
+}
+
+impl<B: Backend> UploadManager<B> {
+    pub(super) unsafe fn new(
+        device_ptr: Rc<RefCell<DeviceState<B>>>,
+        adapter: &AdapterState<B>
+    ) -> Result<Self, RenderError> {
+        let ring = BufferState::new::<u8>(
+            Rc::clone(&device_ptr),
+            STAGING_RING_SIZE,
+            Usage::TRANSFER_SRC,
+            Properties::CPU_VISIBLE | Properties::COHERENT)?;
+        let mapping = ring.mapping()
+            .ok_or_else(|| RenderError::Backend("staging memory is not host visible".to_string()))?;
+
+        let (command_pool, transfer_pool, ownership_transfer) = {
+            let device = device_ptr.borrow();
+            let command_pool = device.device.create_command_pool_typed(
+                &device.queues,
+                CommandPoolCreateFlags::TRANSIENT
+            )?;
+            let transfer_pool = match device.transfer_queues.as_ref() {
+                Some(transfer_queues) => {
+                    let transfer_pool = device.device.create_command_pool_typed(
+                        transfer_queues,
+                        CommandPoolCreateFlags::TRANSIENT
+                    );
+                    match transfer_pool {
+                        Ok(transfer_pool) => Some(transfer_pool),
+                        Err(e) => {
+                            device.device.destroy_command_pool(command_pool.into_raw());
+                            return Err(e.into());
+                        }
+                    }
+                },
+                None => None,
+            };
+            let ownership_transfer = device.transfer_queues
+                .as_ref()
+                .map(|transfer_queues| transfer_queues.family()..device.queues.family());
+            (command_pool, transfer_pool, ownership_transfer)
+        };
+
+        Ok(UploadManager {
+            ring,
+            mapping,
+            head: 0,
+            used: 0,
+            // buffer to image copies need offsets aligned to the texel size
+            offset_alignment: std::cmp::max(
+                adapter.limits.optimal_buffer_copy_offset_alignment, TEXEL_SIZE as u64),
+            pitch_alignment: std::cmp::max(
+                adapter.limits.optimal_buffer_copy_pitch_alignment as u32, 1),
+            recording: None,
+            in_flight: VecDeque::new(),
+            command_pool: Some(command_pool),
+            transfer_pool,
+            ownership_transfer,
+            device: device_ptr,
+        })
+    }
+
+    /// The graphics command buffer of the current batch, for barriers and
+    /// blits that go with the uploads. Runs after the copies.
+    pub(super) unsafe fn commands(&mut self) -> &mut CommandBuffer<B, Graphics, OneShot> {
+        if self.recording.is_none() {
+            let mut commands = self.command_pool
+                .as_mut()
+                .unwrap()
+                .acquire_command_buffer::<OneShot>();
+            commands.begin();
+            let transfers = self.transfer_pool.as_mut().map(|transfer_pool| {
+                let mut transfers = transfer_pool.acquire_command_buffer::<OneShot>();
+                transfers.begin();
+                transfers
+            });
+            self.recording = Some(Recording {
+                commands,
+                transfers,
+                ring_bytes: 0,
+                oversized: vec![],
+            });
+        }
+        &mut self.recording.as_mut().unwrap().commands
+    }
+
+    /// Copies `data_source` to the start of `dst`, which needs
+    /// `TRANSFER_DST` usage, for `access` from `stage` on.
+    pub(super) unsafe fn upload_buffer<T>(
+        &mut self,
+        data_source: &[T],
+        dst: &B::Buffer,
+        stage: PipelineStage,
+        access: BufferAccess
+    ) -> Result<(), RenderError> where T: Copy {
+        let size = (data_source.len() * std::mem::size_of::<T>()) as u64;
+        let bytes = std::slice::from_raw_parts(data_source.as_ptr() as *const u8, size as usize);
+
+        let (source, offset) = self.stage(size, 4, |target| target.copy_from_slice(bytes))?;
+
+        let (mut copies, src) = self.staged(source);
+        copies.copy_buffer(src, dst, command::BufferCopy {
+            src: offset,
+            dst: 0,
+            size
+        });
+
+        if let Some(families) = self.ownership_transfer.clone() {
+            self.transfer_ownership(
+                PipelineStage::TOP_OF_PIPE..stage,
+                Barrier::Buffer {
+                    states: BufferAccess::TRANSFER_WRITE..BufferAccess::empty(),
+                    target: dst,
+                    families: Some(families.clone()),
+                    range: None..None,
+                },
+                Barrier::Buffer {
+                    states: BufferAccess::empty()..access,
+                    target: dst,
+                    families: Some(families),
+                    range: None..None,
+                });
+        }
+        Ok(())
+    }
+
+    /// Copies `levels` into the mip levels of `range` in order, leaving
+    /// them in `TransferDstOptimal` layout.
+    pub(super) unsafe fn upload_image<'a, I>(
+        &mut self,
+        levels: I,
+        dst: &B::Image,
+        range: SubresourceRange
+    ) -> Result<(), RenderError> where I: IntoIterator<Item = &'a image::RgbaImage> {
+        self.commands();
+        self.copies().pipeline_barrier(
+            PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
+            &[Barrier::Image {
+                states: (ImageAccess::empty(), Layout::Undefined)
+                    ..(ImageAccess::TRANSFER_WRITE, Layout::TransferDstOptimal),
+                target: dst,
+                families: None,
+                range: range.clone(),
+            }]);
+
+        for (level, img) in range.levels.clone().zip(levels) {
+            let (width, height) = img.dimensions();
+            let row_size = width * TEXEL_SIZE;
+            let row_alignment_mask = self.pitch_alignment - 1;
+            let row_pitch = (row_size + row_alignment_mask) & !row_alignment_mask;
+            let size = (height * row_pitch) as u64;
+
+            let (source, offset) = self.stage(size, self.offset_alignment, |target| {
+                for (y, row) in img.chunks(row_size as usize).enumerate() {
+                    let dest_base = y * row_pitch as usize;
+                    target[dest_base..dest_base + row.len()].copy_from_slice(row);
+                }
+            })?;
+
+            let (mut copies, src) = self.staged(source);
+            copies.copy_buffer_to_image(src, dst, command::BufferImageCopy {
+                buffer_offset: offset,
+                buffer_width: row_pitch / TEXEL_SIZE,
+                buffer_height: height,
+                image_layers: SubresourceLayers {
+                    aspects: Aspects::COLOR,
+                    level,
+                    layers: range.layers.clone()
+                },
+                image_offset: Offset { x: 0, y: 0, z: 0 },
+                image_extent: Extent {
+                    width,
+                    height,
+                    depth: 1
+                }
+            });
+        }
+
+        if let Some(families) = self.ownership_transfer.clone() {
+            self.transfer_ownership(
+                PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
+                Barrier::Image {
+                    states: (ImageAccess::TRANSFER_WRITE, Layout::TransferDstOptimal)
+                        ..(ImageAccess::empty(), Layout::TransferDstOptimal),
+                    target: dst,
+                    families: Some(families.clone()),
+                    range: range.clone(),
+                },
+                Barrier::Image {
+                    states: (ImageAccess::empty(), Layout::TransferDstOptimal)
+                        ..(ImageAccess::TRANSFER_READ | ImageAccess::TRANSFER_WRITE,
+                           Layout::TransferDstOptimal),
+                    target: dst,
+                    families: Some(families),
+                    range,
+                });
+        }
+        Ok(())
+    }
+
+    /// Submits what was recorded so far. The commands run in the
+    /// background, the next ones are recorded into a new batch.
+    pub(super) unsafe fn flush(&mut self) -> Result<(), RenderError> {
+        if self.recording.is_none() {
+            return Ok(());
+        }
+        let mut device = self.device.borrow_mut();
+        let fence = device.device.create_fence(false)?;
+        let semaphore = if self.ownership_transfer.is_some() {
+            match device.device.create_semaphore() {
+                Ok(semaphore) => Some(semaphore),
+                Err(e) => {
+                    device.device.destroy_fence(fence);
+                    return Err(e.into());
+                }
+            }
+        } else {
+            None
+        };
+
+        let Recording { mut commands, mut transfers, ring_bytes, oversized } =
+            self.recording.take().unwrap();
+        let DeviceState { queues, transfer_queues, .. } = &mut *device;
+
+        if let (Some(transfers), Some(transfer_queues), Some(semaphore)) =
+            (transfers.as_mut(), transfer_queues.as_mut(), semaphore.as_ref()) {
+            transfers.finish();
+            transfer_queues.queues[0].submit(
+                Submission {
+                    command_buffers: std::iter::once(&*transfers),
+                    wait_semaphores: None,
+                    signal_semaphores: std::iter::once(semaphore),
+                },
+                None);
+        }
+
+        commands.finish();
+        queues.queues[0].submit(
+            Submission {
+                command_buffers: std::iter::once(&commands),
+                wait_semaphores: semaphore
+                    .as_ref()
+                    .map(|semaphore| (semaphore, PipelineStage::TOP_OF_PIPE)),
+                signal_semaphores: None,
+            },
+            Some(&fence));
+        debug!("Submitted uploads using {} bytes of staging memory and {} oversized buffers",
+               ring_bytes, oversized.len());
+
+        self.in_flight.push_back(Batch {
+            _commands: commands,
+            _transfers: transfers,
+            fence,
+            semaphore,
+            ring_bytes,
+            _oversized: oversized,
+        });
+        Ok(())
+    }
+
+    /// Blocks until every submitted command is done.
+    pub(super) unsafe fn wait(&mut self) -> Result<(), RenderError> {
+        while !self.in_flight.is_empty() {
+            self.wait_oldest()?;
+        }
+        if self.recording.is_none() {
+            self.command_pool.as_mut().unwrap().reset();
+            if let Some(transfer_pool) = self.transfer_pool.as_mut() {
+                transfer_pool.reset();
+            }
+        }
+        Ok(())
+    }
+
+    /// Submits what was recorded and waits for it and everything before.
+    pub(super) unsafe fn finish(&mut self) -> Result<(), RenderError> {
+        self.flush()?;
+        self.wait()
+    }
+
+    /// Writes `size` bytes with `write` into staging memory that isn't
+    /// reused before the current batch is done, waiting for earlier
+    /// batches if the ring is full. Returns the index of the oversized
+    /// buffer written to, `None` for the ring, and the offset.
+    unsafe fn stage<F>(
+        &mut self,
+        size: u64,
+        alignment: u64,
+        write: F
+    ) -> Result<(Option<usize>, u64), RenderError> where F: FnOnce(&mut [u8]) {
+        self.commands();
+
+        if size > STAGING_RING_SIZE {
+            debug!("Staging {} bytes outside of the ring", size);
+            let buffer = BufferState::new::<u8>(
+                Rc::clone(&self.device),
+                size,
+                Usage::TRANSFER_SRC,
+                Properties::CPU_VISIBLE | Properties::COHERENT)?;
+            let mapping = buffer.mapping()
+                .ok_or_else(|| RenderError::Backend("staging memory is not host visible".to_string()))?;
+            write(std::slice::from_raw_parts_mut(mapping, size as usize));
+
+            let oversized = &mut self.recording.as_mut().unwrap().oversized;
+            oversized.push(buffer);
+            return Ok((Some(oversized.len() - 1), 0));
+        }
+
+        loop {
+            let aligned = align_up(self.head, alignment);
+            let (offset, taken) = if aligned + size <= STAGING_RING_SIZE {
+                (aligned, aligned + size - self.head)
+            } else {
+                // the rest of the ring is skipped
+                (0, STAGING_RING_SIZE - self.head + size)
+            };
+
+            if self.used + taken <= STAGING_RING_SIZE {
+                self.head = offset + size;
+                self.used += taken;
+                self.recording.as_mut().unwrap().ring_bytes += taken;
+                write(std::slice::from_raw_parts_mut(
+                    self.mapping.add(offset as usize), size as usize));
+                return Ok((None, offset));
+            }
+
+            // only the current batch holds the ring, so it has to go first
+            if self.in_flight.is_empty() {
+                self.flush()?;
+                self.commands();
+            }
+            self.wait_oldest()?;
+        }
+    }
+
+    fn copies(&mut self) -> CopyCommands<B> {
+        let recording = self.recording.as_mut().unwrap();
+        match recording.transfers.as_mut() {
+            Some(transfers) => CopyCommands::Transfer(transfers),
+            None => CopyCommands::Graphics(&mut recording.commands),
+        }
+    }
+
+    /// `copies` and the staging buffer `stage` wrote to.
+    fn staged(&mut self, source: Option<usize>) -> (CopyCommands<B>, &B::Buffer) {
+        let recording = self.recording.as_mut().unwrap();
+        let buffer = match source {
+            Some(index) => recording.oversized[index].get_buffer(),
+            None => self.ring.get_buffer(),
+        };
+        let copies = match recording.transfers.as_mut() {
+            Some(transfers) => CopyCommands::Transfer(transfers),
+            None => CopyCommands::Graphics(&mut recording.commands),
+        };
+        (copies, buffer)
+    }
+
+    /// Releases a resource from the transfer queue family after its copies
+    /// and acquires it on the graphics one.
+    unsafe fn transfer_ownership(
+        &mut self,
+        acquire_stages: Range<PipelineStage>,
+        release: Barrier<B>,
+        acquire: Barrier<B>
+    ) {
+        let recording = self.recording.as_mut().unwrap();
+        recording.transfers.as_mut().unwrap().pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::BOTTOM_OF_PIPE,
+            Dependencies::empty(),
+            &[release]);
+        recording.commands.pipeline_barrier(
+            acquire_stages,
+            Dependencies::empty(),
+            &[acquire]);
+    }
+
+    unsafe fn wait_oldest(&mut self) -> Result<(), RenderError> {
+        let batch = match self.in_flight.pop_front() {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        let result = {
+            let device = self.device.borrow();
+            let result = device.device.wait_for_fence(&batch.fence, !0);
+            device.device.destroy_fence(batch.fence);
+            if let Some(semaphore) = batch.semaphore {
+                device.device.destroy_semaphore(semaphore);
+            }
+            result
+        };
+
+        self.used -= batch.ring_bytes;
+        if self.used == 0 {
+            self.head = 0;
+        }
+        result?;
+        Ok(())
+    }
+}
+
+impl<B: Backend> Drop for UploadManager<B> {
+    fn drop(&mut self) {
+        unsafe {
+            // a lost device has nothing running anymore, errors are fine
+            while !self.in_flight.is_empty() {
+                let _ = self.wait_oldest();
+            }
+            self.recording.take();
+            let device = self.device.borrow();
+            device.device.destroy_command_pool(
+                self.command_pool.take().unwrap().into_raw());
+            if let Some(transfer_pool) = self.transfer_pool.take() {
+                device.device.destroy_command_pool(transfer_pool.into_raw());
+            }
+        }
+    }
+}
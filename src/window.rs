@@ -1,4 +1,4 @@
-use crate::consts::APP_TITLE;
+use crate::config::WindowConfig;
 
 #[derive(Debug)]
 pub struct WindowState {
@@ -6,19 +6,18 @@ pub struct WindowState {
     pub wb: Option<winit::WindowBuilder>,
 }
 
-// TODO: config
 pub const DEFAULT_WIDTH: u32 = 1024;
 pub const DEFAULT_HEIGHT: u32 = 768;
 
 impl WindowState {
-    pub fn new() -> WindowState {
+    pub fn new(config: &WindowConfig) -> WindowState {
         let events_loop = winit::EventsLoop::new();
 
         let wb = winit::WindowBuilder::new()
             .with_dimensions(winit::dpi::LogicalSize::new(
-                DEFAULT_WIDTH as _,
-                DEFAULT_HEIGHT as _))
-            .with_title(APP_TITLE);
+                config.width as _,
+                config.height as _))
+            .with_title(config.title.as_str());
 
         WindowState {
             events_loop,
@@ -0,0 +1,107 @@
+use std::env;
+use std::path::PathBuf;
+
+use image::{Rgba, RgbaImage};
+
+/// Maximum per-channel difference allowed before a pixel counts as a
+/// mismatch. Overridden with `GOLDEN_TOLERANCE`.
+pub const DEFAULT_TOLERANCE: u8 = 2;
+
+#[derive(Debug)]
+pub struct ImageDiff {
+    pub mismatched_pixels: usize,
+    pub max_difference: u8,
+    pub diff: RgbaImage,
+}
+
+pub fn tolerance() -> u8 {
+    env::var("GOLDEN_TOLERANCE")
+        .ok()
+        .map(|value| value.parse().expect("GOLDEN_TOLERANCE must be a value from 0 to 255"))
+        .unwrap_or(DEFAULT_TOLERANCE)
+}
+
+/// Compares two images channel by channel. On failure the returned diff
+/// image shows matching pixels dimmed and mismatched pixels in red.
+pub fn compare_images(
+    actual: &RgbaImage,
+    expected: &RgbaImage,
+    tolerance: u8
+) -> Result<(), ImageDiff> {
+    assert_eq!(actual.dimensions(), expected.dimensions(),
+               "golden image dimensions differ");
+
+    let (width, height) = actual.dimensions();
+    let mut diff = RgbaImage::new(width, height);
+    let mut mismatched_pixels = 0;
+    let mut max_difference = 0;
+
+    for (x, y, actual_pixel) in actual.enumerate_pixels() {
+        let expected_pixel = expected.get_pixel(x, y);
+        let difference = (0..4)
+            .map(|c| {
+                (actual_pixel.data[c] as i16 - expected_pixel.data[c] as i16).abs() as u8
+            })
+            .max()
+            .unwrap();
+        max_difference = std::cmp::max(max_difference, difference);
+
+        let diff_pixel = if difference > tolerance {
+            mismatched_pixels += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            let p = expected_pixel.data;
+            Rgba([p[0] / 4, p[1] / 4, p[2] / 4, 255])
+        };
+        diff.put_pixel(x, y, diff_pixel);
+    }
+
+    if mismatched_pixels == 0 {
+        Ok(())
+    } else {
+        Err(ImageDiff { mismatched_pixels, max_difference, diff })
+    }
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.png", name))
+}
+
+fn output_dir() -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target/golden");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Checks `actual` against `tests/golden/<name>.png`. Setting
+/// `GOLDEN_BLESS=1` writes `actual` as the new reference instead.
+pub fn assert_matches_golden(name: &str, actual: &RgbaImage) {
+    let path = golden_path(name);
+
+    if env::var("GOLDEN_BLESS").map(|v| v == "1").unwrap_or(false) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        actual.save(&path).unwrap();
+        return;
+    }
+
+    let expected = match image::open(&path) {
+        Ok(img) => img.to_rgba(),
+        Err(e) => panic!(
+            "Can't read golden image {:?} ({}), run with GOLDEN_BLESS=1 to create it",
+            path, e),
+    };
+
+    if let Err(diff) = compare_images(actual, &expected, tolerance()) {
+        let actual_path = output_dir().join(format!("{}.actual.png", name));
+        let diff_path = output_dir().join(format!("{}.diff.png", name));
+        actual.save(&actual_path).unwrap();
+        diff.diff.save(&diff_path).unwrap();
+        panic!(
+            "{} pixels differ from {:?} (max difference {}), see {:?} and {:?}",
+            diff.mismatched_pixels, path, diff.max_difference,
+            actual_path, diff_path);
+    }
+}
@@ -0,0 +1,55 @@
+extern crate game;
+
+use game::config::{AdapterPreference, AdapterType, Config, PresentMode};
+
+#[test]
+fn missing_fields_use_defaults() {
+    let config = Config::from_toml("
+        [window]
+        width = 640
+
+        [rendering]
+        clear_color = [0.1, 0.2, 0.3, 1.0]
+    ").unwrap();
+
+    assert_eq!(config.window.width, 640);
+    assert_eq!(config.window.height, Config::default().window.height);
+    assert_eq!(config.rendering.clear_color, [0.1, 0.2, 0.3, 1.0]);
+    assert_eq!(config.rendering.frames_in_flight, 2);
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn unknown_fields_are_rejected() {
+    assert!(Config::from_toml("[window]\nwidht = 640").is_err());
+}
+
+#[test]
+fn invalid_values_fail_validation() {
+    let mut config = Config::default();
+    config.rendering.msaa_samples = 3;
+    assert!(config.validate().is_err());
+
+    let mut config = Config::default();
+    config.rendering.frames_in_flight = 0;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn present_mode_is_read_in_lowercase() {
+    let config = Config::from_toml("[rendering]\npresent_mode = \"immediate\"").unwrap();
+    assert_eq!(config.rendering.present_mode, PresentMode::Immediate);
+    assert_eq!("mailbox".parse(), Ok(PresentMode::Mailbox));
+    assert!(Config::from_toml("[rendering]\npresent_mode = \"vsync\"").is_err());
+}
+
+#[test]
+fn adapter_is_an_index_type_or_name() {
+    let adapter = |text: &str| AdapterPreference::from(text.to_string());
+    assert_eq!(adapter("1"), AdapterPreference::Index(1));
+    assert_eq!(adapter("Discrete"), AdapterPreference::Type(AdapterType::Discrete));
+    assert_eq!(adapter("GeForce"), AdapterPreference::Name("GeForce".to_string()));
+
+    let config = Config::from_toml("[rendering]\nadapter = \"integrated\"").unwrap();
+    assert_eq!(config.rendering.adapter, Some(AdapterPreference::Type(AdapterType::Integrated)));
+}
@@ -0,0 +1,113 @@
+extern crate game;
+extern crate image;
+extern crate nalgebra_glm as glm;
+
+mod common;
+
+use image::{Rgba, RgbaImage};
+use game::rendering::UniformBufferObject;
+
+use common::compare_images;
+
+/// The first frame of the windowed viewer: no rotation, camera at (2, 2, 2).
+fn fixed_uniforms(width: u32, height: u32) -> UniformBufferObject {
+    let mut ubo = UniformBufferObject {
+        model: glm::Mat4::identity(),
+        view: glm::look_at(
+            &glm::vec3(2.0, 2.0, 2.0),
+            &glm::vec3(0.0, 0.0, 0.0),
+            &glm::vec3(0.0, 0.0, 1.0)),
+        proj: glm::perspective(
+            width as f32 / height as f32,
+            glm::radians(&glm::vec1(45.0))[0],
+            0.1,
+            100.0)
+    };
+    ubo.proj[1 * 4 + 1] *= -1.0;
+    ubo
+}
+
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+fn fixture(name: &str) -> std::path::PathBuf {
+    std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// Renders `paths` headless at `width` by `height` from the fixed view.
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+fn render_fixed_view(paths: &game::rendering::AssetPaths, width: u32, height: u32) -> RgbaImage {
+    use game::config::Config;
+    use game::rendering;
+
+    let assets = rendering::Assets::load(paths).expect("can't load golden test assets");
+    let Config { rendering: mut config, .. } = Config::default();
+    config.pipeline_cache = false;
+    let (backend, _instance) = rendering::create_headless_backend(&config, width, height)
+        .expect("no usable adapter");
+    let mut renderer = unsafe {
+        rendering::RendererState::new(
+            backend,
+            None,
+            rendering::Scene::new(assets),
+            &config)
+    }.expect("can't create renderer");
+    let (width, height) = renderer.extent();
+    renderer.set_fixed_uniforms(fixed_uniforms(width, height));
+
+    renderer.render_frame().expect("can't render frame")
+}
+
+/// The reference is rendered by lavapipe, so this runs with the
+/// `software` feature and needs `--ignored` otherwise.
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+#[test]
+#[cfg_attr(not(feature = "software"), ignore)]
+fn fixture_fixed_view() {
+    use game::rendering::AssetPaths;
+
+    let frame = render_fixed_view(&AssetPaths {
+        models: vec![fixture("nodes.gltf")],
+        texture: fixture("checker.png"),
+    }, 128, 96);
+
+    common::assert_matches_golden("fixture_fixed_view", &frame);
+}
+
+#[cfg(any(feature = "vulkan", feature = "dx11", feature = "dx12", feature = "metal"))]
+#[test]
+#[ignore] // needs a GPU and the chalet assets in models/ and textures/
+fn chalet_fixed_view() {
+    use game::config::Config;
+    use game::rendering::AssetPaths;
+
+    let Config { window, .. } = Config::default();
+    let frame = render_fixed_view(&AssetPaths::default(), window.width, window.height);
+
+    common::assert_matches_golden("chalet_fixed_view", &frame);
+}
+
+#[test]
+fn identical_images_match() {
+    let img = RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+    assert!(compare_images(&img, &img.clone(), 0).is_ok());
+}
+
+#[test]
+fn differences_within_tolerance_match() {
+    let expected = RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+    let actual = RgbaImage::from_pixel(4, 4, Rgba([12, 19, 30, 255]));
+    assert!(compare_images(&actual, &expected, 2).is_ok());
+    assert!(compare_images(&actual, &expected, 1).is_err());
+}
+
+#[test]
+fn mismatched_pixels_are_marked_in_diff() {
+    let expected = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(1, 2, Rgba([200, 0, 0, 255]));
+
+    let diff = compare_images(&actual, &expected, 2).unwrap_err();
+    assert_eq!(diff.mismatched_pixels, 1);
+    assert_eq!(diff.max_difference, 200);
+    assert_eq!(*diff.diff.get_pixel(1, 2), Rgba([255, 0, 0, 255]));
+    assert_eq!(*diff.diff.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+}
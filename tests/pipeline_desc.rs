@@ -0,0 +1,54 @@
+extern crate game;
+extern crate toml;
+
+use std::collections::HashSet;
+
+use game::rendering::{BlendMode, Comparison, CullFace, PipelineDesc, PolygonMode};
+
+#[test]
+fn missing_fields_describe_the_opaque_pipeline() {
+    let desc: PipelineDesc = toml::from_str("").unwrap();
+    assert_eq!(desc, PipelineDesc::default());
+}
+
+#[test]
+fn transparent_wireframe_desc() {
+    let desc: PipelineDesc = toml::from_str("
+        blend = \"alpha\"
+
+        [raster]
+        polygon_mode = \"line\"
+        cull_face = \"none\"
+
+        [depth]
+        test = \"less_equal\"
+        write = false
+    ").unwrap();
+
+    assert_eq!(desc.blend, BlendMode::Alpha);
+    assert_eq!(desc.raster.polygon_mode, PolygonMode::Line);
+    assert_eq!(desc.raster.cull_face, CullFace::None);
+    assert_eq!(desc.raster.front_face, PipelineDesc::default().raster.front_face);
+    assert_eq!(desc.depth.test, Comparison::LessEqual);
+    assert!(!desc.depth.write);
+    assert_eq!(desc.shaders, PipelineDesc::default().shaders);
+}
+
+#[test]
+fn equal_descs_hash_the_same() {
+    let mut unlit = PipelineDesc::default();
+    unlit.shaders.fragment = Some("unlit.frag".to_string());
+
+    let descs: HashSet<PipelineDesc> = vec![
+        PipelineDesc::default(),
+        unlit.clone(),
+        PipelineDesc::default(),
+        unlit,
+    ].into_iter().collect();
+    assert_eq!(descs.len(), 2);
+}
+
+#[test]
+fn unknown_fields_are_rejected() {
+    assert!(toml::from_str::<PipelineDesc>("[raster]\ncul_face = \"none\"").is_err());
+}
@@ -0,0 +1,38 @@
+//! Runs the whole renderer on a CPU adapter, so resource creation and
+//! drawing are covered on machines without a GPU. Needs a software
+//! Vulkan driver such as lavapipe, found through `VK_ICD_FILENAMES`:
+//! `cargo test --features software --test software`.
+#![cfg(feature = "software")]
+
+extern crate game;
+
+use std::path::PathBuf;
+
+use game::config::Config;
+use game::rendering::{self, AssetPaths, Assets, RendererState, Scene};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+#[test]
+fn renders_fixture_on_software_adapter() {
+    let assets = Assets::load(&AssetPaths {
+        models: vec![fixture("nodes.gltf")],
+        texture: fixture("checker.png"),
+    }).unwrap();
+    let Config { rendering: mut config, .. } = Config::default();
+    config.pipeline_cache = false;
+
+    let (backend, _instance) = rendering::create_headless_backend(&config, 64, 48)
+        .expect("no software adapter, install lavapipe or SwiftShader");
+    let mut renderer = unsafe {
+        RendererState::new(backend, None, Scene::new(assets), &config)
+    }.expect("can't create renderer");
+
+    let frame = renderer.render_frame().expect("can't render frame");
+
+    assert_eq!(frame.dimensions(), (64, 48));
+    let clear = *frame.get_pixel(0, 0);
+    assert!(frame.pixels().any(|pixel| *pixel != clear), "nothing was drawn");
+}